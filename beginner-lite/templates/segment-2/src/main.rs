@@ -23,7 +23,7 @@ use dwm1001::{
     DWM1001,
 };
 use postcard::to_vec;
-use protocol::{Cell, RadioMessages};
+use protocol::{Cell, Coord, RadioMessages};
 
 // These addresses probably don't need to be changed
 // for the class
@@ -32,7 +32,8 @@ const SOURCE_ADDRESS: ShortAddress = ShortAddress(0xABCD);
 const DESTINATION_PAN_ID: PanId = PanId(0x0386);
 
 // Heads up! Your instructor will give you a new Source Address to use
-// for the class. Make sure you update it!
+// for the class. Make sure you update it! Alternatively, the server can
+// hand one out, see `protocol::join::Joiner`.
 const DESTINATION_ADDRESS: ShortAddress = ShortAddress(0x0808);
 
 #[entry]
@@ -64,10 +65,11 @@ fn main() -> ! {
         }
     }
 
-    // First, we need to set the position and color of the pixel we would like to draw
+    // First, we need to set the position and color of the pixel we would like to draw.
+    // Rows and columns start at 1, and go up to 255
     let red_square = Cell {
-        row: 1,
-        column: 1,
+        row: Coord::new(1).unwrap(),
+        column: Coord::new(1).unwrap(),
         red: 200_u8,
         green: 0_u8,
         blue: 0_u8,
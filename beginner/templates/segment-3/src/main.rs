@@ -43,7 +43,8 @@ fn main() -> ! {
         .expect("Failed to initialize DW1000");
 
     // You'll need to set an address. Ask your instructor
-    // for more details
+    // for more details, or get one from the server with
+    // `protocol::join::Joiner`
     let pan_id = PanId(0x0386);
     let short_addr = ShortAddress(0);
 
@@ -7,8 +7,17 @@ use panic_halt;
 
 // String formatting
 use nb::block;
-
-use protocol::{RadioMessages, Cell};
+use core::convert::TryFrom;
+
+use protocol::{
+    auth::{self, Key},
+    join::{Joiner, JOIN_ADDRESS},
+    presence::HEARTBEAT_INTERVAL_MS,
+    Heartbeat,
+    RadioMessages,
+    Cell,
+    Coord,
+};
 use postcard::{to_slice, from_bytes};
 use embedded_timeout_macros::TimeoutError;
 
@@ -34,6 +43,10 @@ const MSGS_PER_SEC: u32 = 8;
 const NOMINAL_INTERVAL_US: u32 = 1_000_000 / MSGS_PER_SEC;
 const JITTER_US: u32 = NOMINAL_INTERVAL_US / 10;
 const TICKS_PER_S: u32 = 1_000_000 / NOMINAL_INTERVAL_US;
+const JOIN_INTERVAL_US: u32 = 500_000;
+// Set this if the server config has a key for our address
+const KEY: Option<Key> = None;
+const HEARTBEAT_TICKS: u32 = HEARTBEAT_INTERVAL_MS * 1_000 / NOMINAL_INTERVAL_US;
 
 #[entry]
 fn main() -> ! {
@@ -48,7 +61,7 @@ fn main() -> ! {
         .expect("Failed to initialize DW1000");
 
     let pan_id = PanId(0x0386);
-    let short_addr = ShortAddress(rng.random_u16() % 16 + 1);
+    let short_addr = ShortAddress(JOIN_ADDRESS);
 
     // Wait for the radio to become ready
     loop {
@@ -68,35 +81,118 @@ fn main() -> ! {
     let mut tx_buf = [0u8; 64];
     let mut rx_buf = [0u8; 1024];
 
+    // Ask the server for an address, identifying ourselves with
+    // the unique device ID of the nRF52
+    let device_id = u64::from(board.FICR.deviceid[0].read().bits())
+        | (u64::from(board.FICR.deviceid[1].read().bits()) << 32);
+    let mut joiner = Joiner::new(device_id);
+
+    let assigned = loop {
+        let msg_buf = to_slice(&joiner.request(), &mut tx_buf).unwrap();
+
+        let mut tx = dw1000
+            .send(
+                msg_buf,
+                mac::Address::Short(pan_id, ShortAddress(0x0808)),
+                None
+            )
+            .expect("Failed to start sending");
+
+        block!(tx.wait())
+            .expect("Failed to send data");
+
+        dw1000 = tx.finish_sending().expect("failed to finish sending");
+
+        timer.start(JOIN_INTERVAL_US);
+
+        let mut rx = dw1000.receive(RxConfig::default())
+            .expect("Failed to start receiver");
+
+        let result = block_timeout!(&mut timer, rx.wait(&mut rx_buf));
+        dw1000 = rx.finish_receiving().expect("finish_receiving failed");
+
+        if let Ok(message) = result {
+            if let Ok(pmsg) = from_bytes::<RadioMessages>(message.frame.payload) {
+                if let Some(assigned) = joiner.receive(&pmsg) {
+                    break assigned;
+                }
+            }
+        }
+    };
+
+    let short_addr = ShortAddress(assigned.short_addr);
+
+    loop {
+        if dw1000.set_address(pan_id, short_addr).is_err() {
+            continue;
+        }
+
+        if let Ok(raddr) = dw1000.get_address() {
+            if raddr == mac::Address::Short(pan_id, short_addr) {
+                break;
+            }
+        }
+    }
+
+    // Fill the whole area we were given
+    let width = usize::from(assigned.partition.width);
+    let height = usize::from(assigned.partition.height);
+
     let mut x = 1;
     let mut y = 1;
 
     let mut dw1000_opt = Some(dw1000);
+    let mut heartbeat_ticks = 0u32;
+    let mut current_addr = assigned.short_addr;
+    let mut pending_pong = None;
 
     loop {
         let jitter = (NOMINAL_INTERVAL_US - JITTER_US) + (rng.random_u32() % (JITTER_US * 2));
         timer.start(jitter);
 
-        let msg = RadioMessages::SetCell(Cell {
-            row: y,
-            column: x,
-            red: rng.random_u8(),
-            green: rng.random_u8(),
-            blue: rng.random_u8(),
-        });
+        heartbeat_ticks += 1;
 
-        x += 1;
+        let msg = if let Some(value) = pending_pong.take() {
+            // Answer the modem's link test. This waits for our next tick,
+            // so the round trip time includes up to one interval.
+            RadioMessages::Pong(value)
+        } else if heartbeat_ticks >= HEARTBEAT_TICKS {
+            // Let the server know we're still around
+            heartbeat_ticks = 0;
 
-        if x > 8 {
-            x = 1;
-            y += 1;
-        }
+            RadioMessages::Heartbeat(Heartbeat {
+                battery_mv: None,
+                firmware: Some("client-tester".into()),
+            })
+        } else {
+            let msg = RadioMessages::SetCell(Cell {
+                // Our partition is within the board, so this can't fail
+                row: Coord::try_from(y).unwrap(),
+                column: Coord::try_from(x).unwrap(),
+                red: rng.random_u8(),
+                green: rng.random_u8(),
+                blue: rng.random_u8(),
+            });
+
+            x += 1;
+
+            if x > width {
+                x = 1;
+                y += 1;
+            }
 
-        if y > 8 {
-            y = 1;
-        }
+            if y > height {
+                y = 1;
+            }
 
-        let msg_buf = to_slice(&msg, &mut tx_buf).unwrap();
+            msg
+        };
+
+        let len = to_slice(&msg, &mut tx_buf).unwrap().len();
+        let msg_buf = match KEY {
+            Some(ref key) => auth::sign(key, current_addr, &mut tx_buf, len).unwrap(),
+            None => &tx_buf[..len],
+        };
 
         let addr = mac::Address::Short(
             PanId(0x0386),
@@ -140,7 +236,9 @@ fn main() -> ! {
         match result {
             Ok(message) => {
                 if let Ok(pmsg) = from_bytes::<RadioMessages>(message.frame.payload) {
-                    if let RadioMessages::StartTurn(addr) = pmsg {
+                    if let RadioMessages::Ping(value) = pmsg {
+                        pending_pong = Some(value);
+                    } else if let RadioMessages::StartTurn(addr) = pmsg {
 
                         if toggle2 {
                             board.leds.D11.enable();
@@ -151,6 +249,7 @@ fn main() -> ! {
 
                         let pan_id = PanId(0x0386);
                         let short_addr = ShortAddress(addr);
+                        current_addr = addr;
 
                         // I am become turn
                         'addr: loop {
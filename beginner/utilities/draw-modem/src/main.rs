@@ -19,6 +19,7 @@ use dwm1001::{
             TIMER0,
             TIMER1,
             SPIM2,
+            RTC0,
         },
         uarte::Baudrate as UartBaudrate,
     },
@@ -28,7 +29,7 @@ use dwm1001::{
     DW_RST,
     block_timeout,
     dw1000::{
-        mac::{Address, PanId, ShortAddress},
+        mac::{Address, FrameType as MacFrameType, PanId, ShortAddress},
         hl::RxConfig,
         Message,
     },
@@ -44,8 +45,34 @@ use panic_ramdump as _;
 // Workspace dependencies
 use protocol::{
     ModemUartMessages,
+    CheckedFrame,
+    MAX_UART_MESSAGE,
     CellCommand,
+    DrawCommand,
+    BlitCommand,
+    HeartbeatCommand,
+    PongReport,
+    Reception,
+    QueryCommand,
     RadioMessages,
+    RadioFrame,
+    FrameHeader,
+    FrameType,
+    FrameAddress,
+    FrameRejection,
+    SniffedFrame,
+    RxQuality,
+    ModemSettings,
+    ModemStatus,
+    DataRate,
+    Hello,
+    Version,
+    PROTOCOL_VERSION,
+    auth::{self, Keyring},
+    envelope,
+    fragment::Reassembler,
+    join::JOIN_ADDRESS,
+    capabilities,
 };
 use nrf52_bin_logger::{
     Logger,
@@ -53,17 +80,28 @@ use nrf52_bin_logger::{
     receivers::RealReceiver,
 };
 
-const RX_PERIOD_US: u32 = 100_000;
 const IDLE_WARNING_US: u32 = 1_000_000;
-const IDLE_STEPDOWN: u32 = IDLE_WARNING_US / RX_PERIOD_US;
+const STATUS_INTERVAL_MS: u32 = 5_000;
+const MIN_RX_WINDOW_US: u32 = 1_000;
+const REASSEMBLY_TIMEOUT_MS: u32 = 500;
+
+// The only channel and data rate supported by the dw1000 driver
+const SUPPORTED_CHANNEL: u8 = 5;
+const SUPPORTED_DATA_RATE: DataRate = DataRate::Mbps6_8;
+
+type ModemRadio = DW<
+    Spim<SPIM2>,
+    P0_17<Output<PushPull>>,
+    dw1000::Ready,
+>;
+
 type ModemLogger = Logger<
-    // Send logs + ModemUartMessages, max outgoing serialized
-    // message size is 128 bytes
-    RealSender<ModemUartMessages, U128>,
+    // Send logs + ModemUartMessages wrapped in a CheckedFrame,
+    // max outgoing serialized message size is 512 bytes
+    RealSender<CheckedFrame, U512>,
 
-    // Receive ModemUartMessages, max incoming serialized message
-    // size is 256 bytes, store up to 8 parsed messages
-    RealReceiver<ModemUartMessages, U2048, U64>,
+    // Receive ModemUartMessages wrapped in a CheckedFrame
+    RealReceiver<CheckedFrame, U2048, U64>,
 >;
 
 
@@ -73,13 +111,10 @@ const APP: () = {
     static mut TIMER_1:     Timer<TIMER0>             = ();
     static mut TIMER_2:     Timer<TIMER1>             = ();
     static mut LOGGER:    ModemLogger = ();
-    static mut DW1000:    Option<DW<
-                            Spim<SPIM2>,
-                            P0_17<Output<PushPull>>,
-                            dw1000::Ready,
-                          >> = ();
+    static mut DW1000:    Option<ModemRadio> = ();
     static mut DW_RST_PIN: DW_RST                   = ();
     static mut RANDOM:     Rng                      = ();
+    static mut UPTIME:     Uptime                   = ();
 
     #[init]
     fn init() {
@@ -100,6 +135,10 @@ const APP: () = {
 
         let rng = device.RNG.constrain();
 
+        // The RTC runs from the low frequency clock
+        device.CLOCK.tasks_lfclkstart.write(|w| unsafe { w.bits(1) });
+        let uptime = Uptime::new(device.RTC0);
+
         let dw1000 = new_dw1000(
             device.SPIM2,
             pins.p0_16,
@@ -117,19 +156,11 @@ const APP: () = {
         let mut dw1000 = dw1000.init().unwrap();
 
         // Wait for the radio to become ready
-        loop {
-            if dw1000.set_address(MODEM_PAN, MODEM_ADDR).is_err() {
-                continue;
-            }
-
-            if let Ok(raddr) = dw1000.get_address() {
-                if raddr == Address::Short(MODEM_PAN, MODEM_ADDR) {
-                    break;
-                }
-            }
-        }
+        let settings = ModemSettings::default();
+        set_address(&mut dw1000, PanId(settings.pan_id), ShortAddress(settings.short_addr));
 
         RANDOM = rng;
+        UPTIME = uptime;
         DW_RST_PIN = rst_pin;
         DW1000 = Some(dw1000);
         LOGGER = Logger::new(uarte0);
@@ -138,14 +169,22 @@ const APP: () = {
         LED_RED_1 = pins.p0_14.degrade().into_push_pull_output(Level::High);
     }
 
-    #[idle(resources = [TIMER_1, TIMER_2, LED_RED_1, LOGGER, RANDOM, DW1000])]
+    #[idle(resources = [TIMER_1, TIMER_2, LED_RED_1, LOGGER, RANDOM, DW1000, UPTIME])]
     fn idle() -> ! {
         let mut buffer = [0u8; 1024];
         let mut strbuf: String<U1024> = String::new();
         let mut idle_ctr = 0u32;
         let mut toggle = false;
+        let mut capture = false;
+        let mut sniffer = false;
+        let mut settings = ModemSettings::default();
+        let mut stats = ModemStatus::default();
+        let mut last_status_ms = 0u32;
+        let mut reassembler = Reassembler::new(REASSEMBLY_TIMEOUT_MS);
+        let mut keyring = Keyring::new();
 
         resources.LOGGER.start_receive().unwrap();
+        send(resources.LOGGER, ModemUartMessages::Hello(hello())).unwrap();
         resources.TIMER_2.start(250_000u32);
 
         loop {
@@ -153,8 +192,34 @@ const APP: () = {
             if resources.TIMER_2.wait().is_err() {
                 resources.TIMER_2.start(250_000u32);
 
+                let now_ms = resources.UPTIME.millis();
+                reassembler.expire(now_ms);
+                stats.reassembly_timeouts = reassembler.timeouts();
+
+                if now_ms.wrapping_sub(last_status_ms) >= STATUS_INTERVAL_MS {
+                    send_status(resources.LOGGER, &mut stats, now_ms);
+                    last_status_ms = now_ms;
+                }
+
                 if resources.LOGGER.service_receive().unwrap() > 0 {
-                    while let Some(msg) = resources.LOGGER.get_msg() {
+                    while let Some(frame) = resources.LOGGER.get_msg() {
+                        let msg = match frame.check().map(from_bytes::<ModemUartMessages>) {
+                            Ok(Ok(msg)) => msg,
+                            Ok(Err(_)) => {
+                                if envelope::unsupported(&frame.bytes, ModemUartMessages::VARIANTS).is_some() {
+                                    resources.LOGGER.warn("Unsupported command, skipped").unwrap();
+                                } else {
+                                    resources.LOGGER.warn("Failed to decode command!").unwrap();
+                                }
+                                continue;
+                            }
+                            Err(_) => {
+                                stats.crc_errors = stats.crc_errors.wrapping_add(1);
+                                resources.LOGGER.warn("Bad CRC!").unwrap();
+                                continue;
+                            }
+                        };
+
                         if toggle {
                             #[allow(deprecated)]
                             resources.LED_RED_1.set_low();
@@ -165,27 +230,87 @@ const APP: () = {
                         toggle = !toggle;
 
                         match msg {
-                            x @ ModemUartMessages::Loopback(_) => {
-                                resources.LOGGER.data(x).unwrap();
+                            x @ ModemUartMessages::Loopback(_)
+                            | x @ ModemUartMessages::LoadLoopBack(_) => {
+                                send(resources.LOGGER, x).unwrap();
+                            }
+                            ModemUartMessages::SetCapture(enable) => {
+                                capture = enable;
+                                send(resources.LOGGER, ModemUartMessages::SetCapture(capture)).unwrap();
+                            }
+                            ModemUartMessages::SetSniffer(enable) => {
+                                sniffer = enable;
+                                send(resources.LOGGER, ModemUartMessages::SetSniffer(sniffer)).unwrap();
+                            }
+                            ModemUartMessages::Configure(requested) => {
+                                settings = apply_settings(
+                                    resources.DW1000.as_mut().expect("cfg: dw1000 gone"),
+                                    requested,
+                                );
+                                send(resources.LOGGER, ModemUartMessages::Configured(settings)).unwrap();
+                            }
+                            ModemUartMessages::SetKey(client_key) => {
+                                if keyring.set(client_key.client, client_key.key).is_err() {
+                                    resources.LOGGER.error("Keyring full!").unwrap();
+                                }
+                            }
+                            ModemUartMessages::Hello(_) => {
+                                send(resources.LOGGER, ModemUartMessages::Hello(hello())).unwrap();
+                            }
+                            ModemUartMessages::GetStatus => {
+                                let now_ms = resources.UPTIME.millis();
+                                send_status(resources.LOGGER, &mut stats, now_ms);
                             }
                             ModemUartMessages::AnnounceTurn(id) => {
-                                let msg = RadioMessages::StartTurn(id);
-                                let msg_buf = to_slice(&msg, &mut buffer).unwrap();
-
-                                let mut tx = resources.DW1000
-                                    .take()
-                                    .expect("tx: dw1000 gone")
-                                    .send(
-                                        msg_buf,
-                                        Address::Short(PanId(0x0386), ShortAddress::broadcast()),
-                                        None,
-                                    )
-                                    .expect("Failed to start send");
-
-                                block!(tx.wait())
-                                    .expect("Failed to send data");
-
-                                *resources.DW1000 = Some(tx.finish_sending().expect("failed to finish tx"));
+                                transmit(
+                                    resources.DW1000,
+                                    &mut buffer,
+                                    &RadioMessages::StartTurn(id),
+                                    Address::Short(PanId(settings.broadcast_pan), ShortAddress::broadcast()),
+                                );
+                            }
+                            ModemUartMessages::Ping(dest) => {
+                                // The client echoes the send time in its `Pong`
+                                let now_us = resources.UPTIME.micros();
+                                transmit(
+                                    resources.DW1000,
+                                    &mut buffer,
+                                    &RadioMessages::Ping(now_us),
+                                    Address::Short(PanId(settings.pan_id), ShortAddress(dest)),
+                                );
+                            }
+                            ModemUartMessages::Acknowledge(ack) => {
+                                transmit(
+                                    resources.DW1000,
+                                    &mut buffer,
+                                    &ack.to_radio(),
+                                    Address::Short(PanId(settings.pan_id), ShortAddress(ack.client)),
+                                );
+                            }
+                            ModemUartMessages::Reply(reply) => {
+                                let client = reply.client;
+                                transmit(
+                                    resources.DW1000,
+                                    &mut buffer,
+                                    &RadioMessages::CellState(reply.state),
+                                    Address::Short(PanId(settings.pan_id), ShortAddress(client)),
+                                );
+                            }
+                            ModemUartMessages::Assign(assigned) => {
+                                transmit(
+                                    resources.DW1000,
+                                    &mut buffer,
+                                    &RadioMessages::Assigned(assigned),
+                                    Address::Short(PanId(settings.pan_id), ShortAddress(JOIN_ADDRESS)),
+                                );
+                            }
+                            ModemUartMessages::Reject(reject) => {
+                                transmit(
+                                    resources.DW1000,
+                                    &mut buffer,
+                                    &RadioMessages::Rejected(reject.rejected),
+                                    Address::Short(PanId(settings.pan_id), ShortAddress(reject.client)),
+                                );
                             }
                             _ => {
                                 resources.LOGGER.error("Unexpected Cobs!").unwrap();
@@ -197,15 +322,21 @@ const APP: () = {
             }
 
             let dw1000 = resources.DW1000.take().expect("rx: dw1000 gone");
-            let mut rx = if let Ok(rx) = dw1000.receive(RxConfig::default()) {
+            let rx_cfg = RxConfig {
+                // In sniffer mode, receive frames for all addresses
+                frame_filtering: !sniffer,
+                .. RxConfig::default()
+            };
+            let mut rx = if let Ok(rx) = dw1000.receive(rx_cfg) {
                 rx
             } else {
+                stats.rx_errors = stats.rx_errors.wrapping_add(1);
                 resources.LOGGER.warn("Failed to start receive!").unwrap();
                 resources.TIMER_1.delay(250_000);
                 continue;
             };
 
-            resources.TIMER_1.start(RX_PERIOD_US);
+            resources.TIMER_1.start(settings.rx_window_us);
 
             let result = block_timeout!(&mut *resources.TIMER_1, rx.wait(&mut buffer));
             *resources.DW1000 = Some(rx.finish_receiving().expect("failed to finish rx"));
@@ -213,26 +344,64 @@ const APP: () = {
                 Ok(message) => {
                     // Reset idle ctr
                     idle_ctr = 0;
+                    stats.frames_received = stats.frames_received.wrapping_add(1);
+
+                    if capture && !sniffer {
+                        send(resources.LOGGER, ModemUartMessages::RadioFrame(
+                            radio_frame(&message)
+                        )).unwrap();
+                    }
 
-                    if let Ok(resp) = process_message(
+                    let now_ms = resources.UPTIME.millis();
+                    let rx = Reception {
+                        timestamp_us: resources.UPTIME.micros(),
+                        quality: rx_quality(resources.DW1000),
+                    };
+                    let result = process_message(
                         resources.LOGGER,
+                        &settings,
+                        &keyring,
+                        &mut reassembler,
+                        now_ms,
+                        rx,
                         &message
-                    ) {
-                        resources.LOGGER.data(resp).unwrap();
-                    } else {
-                        strbuf.clear();
-                        write!(&mut strbuf, "^ Bad message from src {:?}", message.frame.header.source).unwrap();
-                        resources.LOGGER.warn(strbuf.as_str()).unwrap();
+                    );
+
+                    if sniffer {
+                        send(resources.LOGGER, ModemUartMessages::Sniffed(SniffedFrame {
+                            frame: radio_frame(&message),
+                            rejection: result.as_ref().err().cloned(),
+                        })).unwrap();
+                    }
+
+                    match result {
+                        Ok(Some(resp)) => {
+                            send(resources.LOGGER, resp).unwrap();
+                        }
+                        // Waiting for more fragments
+                        Ok(None) => {}
+                        // Everything is addressed to someone else in sniffer
+                        // mode, don't flood the log with it
+                        Err(FrameRejection::NotForModem) if sniffer => {
+                            stats.rejected.count(FrameRejection::NotForModem);
+                        }
+                        Err(rejection) => {
+                            stats.rejected.count(rejection);
+
+                            if rejection == FrameRejection::NotForModem {
+                                resources.LOGGER.error("that ain't me").unwrap();
+                            }
+
+                            strbuf.clear();
+                            write!(&mut strbuf, "^ Bad message from src {:?}", message.frame.header.source).unwrap();
+                            resources.LOGGER.warn(strbuf.as_str()).unwrap();
+                        }
                     }
                 },
                 Err(TimeoutError::Timeout) => {
                     idle_ctr += 1;
 
-                    if idle_ctr >= IDLE_STEPDOWN {
-                        strbuf.clear();
-                        let (lbyt, lmsg) = resources.LOGGER.get_stats();
-                        write!(&mut strbuf, "Lost: {} bytes, {} msgs", lbyt, lmsg).unwrap();
-                        resources.LOGGER.warn(strbuf.as_str()).unwrap();
+                    if idle_ctr >= IDLE_WARNING_US / settings.rx_window_us {
                         resources.LOGGER.log("RX Timeout 1s").unwrap();
                         idle_ctr = 0;
                     }
@@ -241,6 +410,7 @@ const APP: () = {
                     continue;
                 }
                 Err(TimeoutError::Other(error)) => {
+                    stats.rx_errors = stats.rx_errors.wrapping_add(1);
                     strbuf.clear();
                     write!(&mut strbuf, "RX: {:?}", error).unwrap();
                     resources.LOGGER.error(strbuf.as_str()).unwrap();
@@ -251,16 +421,163 @@ const APP: () = {
     }
 };
 
-const MODEM_PAN: PanId = PanId(0x0386);
-const MODEM_ADDR: ShortAddress = ShortAddress(0x0808);
 const BROADCAST: PanId = PanId(0xffff);
 
-fn process_message(logger: &mut ModemLogger, msg: &Message) -> Result<ModemUartMessages, ()> {
+/// Uptime, counted by the free running RTC0
+pub struct Uptime {
+    rtc: RTC0,
+    last: u32,
+    ticks: u64,
+}
+
+impl Uptime {
+    const TICKS_PER_SEC: u64 = 32_768;
+    const COUNTER_MASK: u32 = 0x00FF_FFFF;
+
+    fn new(rtc: RTC0) -> Self {
+        rtc.prescaler.write(|w| unsafe { w.prescaler().bits(0) });
+        rtc.tasks_start.write(|w| unsafe { w.bits(1) });
+
+        Uptime {
+            rtc,
+            last: 0,
+            ticks: 0,
+        }
+    }
+
+    /// Must be called at least once every 512 seconds, when
+    /// the 24 bit RTC counter wraps around
+    fn ticks(&mut self) -> u64 {
+        let now = self.rtc.counter.read().bits() & Self::COUNTER_MASK;
+        self.ticks += u64::from(now.wrapping_sub(self.last) & Self::COUNTER_MASK);
+        self.last = now;
+
+        self.ticks
+    }
+
+    fn millis(&mut self) -> u32 {
+        (self.ticks() * 1000 / Self::TICKS_PER_SEC) as u32
+    }
+
+    /// Wraps around after about 71 minutes
+    fn micros(&mut self) -> u32 {
+        (self.ticks() * 1_000_000 / Self::TICKS_PER_SEC) as u32
+    }
+}
+
+fn hello() -> Hello {
+    Hello {
+        protocol_version: PROTOCOL_VERSION,
+        firmware_version: Version {
+            major: env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+            minor: env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+            patch: env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+        },
+        capabilities: capabilities::CAPTURE
+            | capabilities::SNIFFER
+            | capabilities::CONFIGURE
+            | capabilities::STATUS
+            | capabilities::AUTH
+            | capabilities::LOAD_LOOPBACK
+            | capabilities::RADIO_PING,
+    }
+}
+
+fn send_status(logger: &mut ModemLogger, stats: &mut ModemStatus, now_ms: u32) {
+    let (lost_bytes, lost_msgs) = logger.get_stats();
+
+    stats.uptime_ms = now_ms;
+    stats.lost_bytes = lost_bytes as u32;
+    stats.lost_msgs = lost_msgs as u32;
+
+    send(logger, ModemUartMessages::Status(*stats)).unwrap();
+}
+
+/// Send a message to the host, wrapped in a `CheckedFrame`
+fn send(logger: &mut ModemLogger, msg: ModemUartMessages) -> Result<(), ()> {
+    let mut buf = [0u8; MAX_UART_MESSAGE];
+    let bytes = to_slice(&msg, &mut buf).map_err(|_| ())?;
+    let frame = CheckedFrame::new(bytes).map_err(|_| ())?;
+
+    logger.data(frame).map_err(|_| ())
+}
+
+fn transmit(dw1000: &mut Option<ModemRadio>, buffer: &mut [u8], msg: &RadioMessages, dest: Address) {
+    let msg_buf = to_slice(msg, buffer).unwrap();
+
+    let mut tx = dw1000
+        .take()
+        .expect("tx: dw1000 gone")
+        .send(
+            msg_buf,
+            dest,
+            None,
+        )
+        .expect("Failed to start send");
+
+    block!(tx.wait())
+        .expect("Failed to send data");
+
+    *dw1000 = Some(tx.finish_sending().expect("failed to finish tx"));
+}
+
+fn set_address(dw1000: &mut ModemRadio, pan: PanId, addr: ShortAddress) {
+    loop {
+        if dw1000.set_address(pan, addr).is_err() {
+            continue;
+        }
+
+        if let Ok(raddr) = dw1000.get_address() {
+            if raddr == Address::Short(pan, addr) {
+                break;
+            }
+        }
+    }
+}
+
+/// Apply the requested settings, as far as they are supported.
+/// Returns the settings now in effect.
+fn apply_settings(dw1000: &mut ModemRadio, requested: ModemSettings) -> ModemSettings {
+    set_address(dw1000, PanId(requested.pan_id), ShortAddress(requested.short_addr));
+
+    ModemSettings {
+        channel: SUPPORTED_CHANNEL,
+        data_rate: SUPPORTED_DATA_RATE,
+        rx_window_us: requested.rx_window_us.max(MIN_RX_WINDOW_US).min(IDLE_WARNING_US),
+        .. requested
+    }
+}
+
+/// Receive quality of the last frame, from the diagnostic registers of the DW1000
+fn rx_quality(dw1000: &mut Option<ModemRadio>) -> RxQuality {
+    let ll = dw1000.as_mut().expect("quality: dw1000 gone").ll();
+
+    match (ll.rx_fqual().read(), ll.rx_time().read(), ll.rx_finfo().read()) {
+        (Ok(fqual), Ok(time), Ok(finfo)) => RxQuality {
+            cir_power: fqual.cir_pwr(),
+            fp_ampl: [time.fp_ampl1(), fqual.fp_ampl2(), fqual.fp_ampl3()],
+            preamble_count: finfo.rxpacc(),
+            std_noise: fqual.std_noise(),
+            prf_64mhz: finfo.rxprfr() == 0b10,
+        },
+        _ => RxQuality::default(),
+    }
+}
+
+fn process_message(
+    logger: &mut ModemLogger,
+    settings: &ModemSettings,
+    keyring: &Keyring,
+    reassembler: &mut Reassembler,
+    now_ms: u32,
+    rx: Reception,
+    msg: &Message,
+) -> Result<Option<ModemUartMessages>, FrameRejection> {
     let (src_pan, src_addr) = match msg.frame.header.source {
         Address::Short(pan, addr) => (pan, addr),
         _ => {
             logger.error("bad src addr!").unwrap();
-            return Err(())
+            return Err(FrameRejection::BadSource)
         }
     };
 
@@ -268,48 +585,201 @@ fn process_message(logger: &mut ModemLogger, msg: &Message) -> Result<ModemUartM
         Address::Short(pan, addr) => (pan, addr),
         _ => {
             logger.error("bad dest addr!").unwrap();
-            return Err(())
+            return Err(FrameRejection::BadDestination)
         }
     };
 
     if src_pan == BROADCAST {
         logger.error("bad bdcst pan!").unwrap();
-        return Err(())
+        return Err(FrameRejection::BroadcastPan)
     }
 
     if src_addr == ShortAddress::broadcast() {
         logger.error("bad bdcst addr!").unwrap();
-        return Err(())
+        return Err(FrameRejection::BroadcastSource)
     }
 
     if dst_pan != src_pan {
         logger.error("mismatch pan!").unwrap();
-        return Err(())
+        return Err(FrameRejection::PanMismatch)
     }
 
-    if msg.frame.header.destination != Address::Short(MODEM_PAN, MODEM_ADDR) {
-        logger.error("that ain't me").unwrap();
-        return Err(())
+    if msg.frame.header.destination != Address::Short(PanId(settings.pan_id), ShortAddress(settings.short_addr)) {
+        return Err(FrameRejection::NotForModem)
     }
 
-    if let Ok(pmsg) = from_bytes::<RadioMessages>(msg.frame.payload) {
-        match pmsg {
-            RadioMessages::SetCell(sc) => {
-                return Ok(ModemUartMessages::SetCell(CellCommand {
-                    source: src_addr.0,
-                    dest: dst_addr.0,
-                    cell: sc,
-                }));
+    // Clients with a key must sign every frame
+    let payload = match keyring.get(src_addr.0) {
+        Some(key) => match auth::verify(key, src_addr.0, msg.frame.payload) {
+            Ok(payload) => payload,
+            Err(_) => {
+                logger.warn("Bad tag!").unwrap();
+                return Err(FrameRejection::BadTag)
             }
-            RadioMessages::StartTurn(_) => {
-                logger.warn("ClientMSGS_PER_SEC tried to annouce turn!").unwrap();
+        },
+        None => msg.frame.payload,
+    };
+
+    let pmsg = match from_bytes::<RadioMessages>(payload) {
+        Ok(pmsg) => pmsg,
+        Err(_) => return Err(undecodable(logger, payload)),
+    };
+
+    if let RadioMessages::Fragment(ref fragment) = pmsg {
+        return match reassembler.push(src_addr.0, fragment, now_ms) {
+            Ok(Some(data)) => match from_bytes::<RadioMessages>(data) {
+                // The reception of the fragment which completed the message
+                Ok(pmsg) => forward(logger, src_addr.0, dst_addr.0, rx, pmsg).map(Some),
+                Err(_) => Err(undecodable(logger, data)),
+            },
+            Ok(None) => Ok(None),
+            Err(_) => {
+                logger.warn("Bad fragment!").unwrap();
+                Err(FrameRejection::BadFragment)
             }
-        }
+        };
+    }
+
+    // Answers to our `Ping`s carry the time they were sent
+    if let RadioMessages::Pong(sent_us) = pmsg {
+        return Ok(Some(ModemUartMessages::Pong(PongReport {
+            source: src_addr.0,
+            rtt_us: rx.timestamp_us.wrapping_sub(sent_us),
+            quality: rx.quality,
+        })));
+    }
+
+    forward(logger, src_addr.0, dst_addr.0, rx, pmsg).map(Some)
+}
+
+/// Why a message from a client could not be decoded
+fn undecodable(logger: &mut ModemLogger, payload: &[u8]) -> FrameRejection {
+    if envelope::unsupported(payload, RadioMessages::VARIANTS).is_some() {
+        logger.warn("Unsupported message, skipped").unwrap();
+        FrameRejection::Unsupported
     } else {
         logger.warn("Failed to decode!").unwrap();
+        FrameRejection::DecodeFailed
     }
+}
 
-    Err(())
+/// Turn a complete message from a client into the message for the host
+fn forward(
+    logger: &mut ModemLogger,
+    source: u16,
+    dest: u16,
+    rx: Reception,
+    pmsg: RadioMessages,
+) -> Result<ModemUartMessages, FrameRejection> {
+    match pmsg {
+        RadioMessages::SetCell(sc) => {
+            Ok(ModemUartMessages::SetCell(CellCommand {
+                source,
+                dest,
+                rx,
+                cell: sc,
+            }))
+        }
+        RadioMessages::StartTurn(_) => {
+            logger.warn("ClientMSGS_PER_SEC tried to annouce turn!").unwrap();
+            Err(FrameRejection::Unexpected)
+        }
+        RadioMessages::Blit(blit) => {
+            Ok(ModemUartMessages::Blit(BlitCommand {
+                source,
+                dest,
+                rx,
+                blit,
+            }))
+        }
+        RadioMessages::Sequenced(msg) => {
+            Ok(ModemUartMessages::Draw(DrawCommand {
+                source,
+                dest,
+                rx,
+                shape: msg.shape,
+                seq: Some(msg.seq),
+            }))
+        }
+        RadioMessages::GetCell(pos) => {
+            Ok(ModemUartMessages::Query(QueryCommand {
+                source,
+                dest,
+                rx,
+                region: pos.into(),
+            }))
+        }
+        RadioMessages::GetRegion(region) => {
+            Ok(ModemUartMessages::Query(QueryCommand {
+                source,
+                dest,
+                rx,
+                region,
+            }))
+        }
+        RadioMessages::Join(join) => {
+            Ok(ModemUartMessages::Join(join))
+        }
+        RadioMessages::Heartbeat(heartbeat) => {
+            Ok(ModemUartMessages::Heartbeat(HeartbeatCommand {
+                source,
+                rx,
+                heartbeat,
+            }))
+        }
+        RadioMessages::Ping(_) | RadioMessages::Pong(_) => {
+            logger.warn("Client sent a ping, or a fragmented pong!").unwrap();
+            Err(FrameRejection::Unexpected)
+        }
+        // Fragments may not be nested, and only the modem acknowledges
+        bulk => match bulk.into_shape() {
+            Some(shape) => Ok(ModemUartMessages::Draw(DrawCommand {
+                source,
+                dest,
+                rx,
+                shape,
+                seq: None,
+            })),
+            None => Err(FrameRejection::Unexpected),
+        },
+    }
+}
+
+fn radio_frame(msg: &Message) -> RadioFrame {
+    let header = &msg.frame.header;
+
+    let mut payload = heapless::Vec::new();
+
+    // The payload of a valid 802.15.4 frame always fits, but
+    // truncate rather than drop the frame if it doesn't
+    let len = msg.frame.payload.len().min(payload.capacity());
+    payload.extend_from_slice(&msg.frame.payload[..len]).ok();
+
+    RadioFrame {
+        header: FrameHeader {
+            frame_type: match header.frame_type {
+                MacFrameType::Beacon => FrameType::Beacon,
+                MacFrameType::Data => FrameType::Data,
+                MacFrameType::Acknowledgement => FrameType::Acknowledgement,
+                MacFrameType::MacCommand => FrameType::MacCommand,
+            },
+            frame_pending: header.frame_pending,
+            ack_request: header.ack_request,
+            pan_id_compress: header.pan_id_compress,
+            seq: header.seq,
+            destination: frame_address(header.destination),
+            source: frame_address(header.source),
+        },
+        payload,
+    }
+}
+
+fn frame_address(addr: Address) -> FrameAddress {
+    match addr {
+        Address::None => FrameAddress::None,
+        Address::Short(pan, addr) => FrameAddress::Short { pan: pan.0, addr: addr.0 },
+        Address::Extended(pan, addr) => FrameAddress::Extended { pan: pan.0, addr: addr.0 },
+    }
 }
 
 use nb::{
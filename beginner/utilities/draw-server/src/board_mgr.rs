@@ -2,15 +2,38 @@ use std::time::{Duration, Instant};
 use std::sync::mpsc::{Sender, Receiver, RecvTimeoutError};
 use reqwest;
 use std::collections::hash_map::HashMap;
+use std::convert::TryFrom;
 use std::ops::RangeInclusive;
 
-use protocol::{CellCommand, Cell, ModemUartMessages};
+use protocol::{
+    Acknowledge, Assigned, CellCommand, Cell, CellState, Color, Coord, DrawCommand, Heartbeat, HeartbeatCommand,
+    Join, ModemUartMessages,
+    QueryCommand, Reception, Region, RejectCommand, RejectReason, Rejected, ReplyCommand, Shape,
+    BLOCKED_PATH_DB, query::STATE_CELLS,
+};
 use rand::Rng;
+use log::{debug, error, info, warn};
+
+use crate::logging;
 
 use serde::Deserialize;
 
 type Partitions = HashMap<u16, Segment>;
 
+/// Largest number of cells answered per query, larger regions are cut short
+const MAX_QUERY_CELLS: usize = 256;
+
+/// Addresses handed out to joining boards in `FreeDraw` mode
+const FREE_DRAW_ADDRESSES: RangeInclusive<u16> = 1..=255;
+
+/// Messages from clients, as forwarded by the modem
+pub enum Request {
+    Draw(DrawCommand),
+    Query(QueryCommand),
+    Join(Join),
+    Heartbeat(HeartbeatCommand),
+}
+
 #[derive(Deserialize, Debug)]
 enum BoardMode {
     FreeDraw {
@@ -24,6 +47,11 @@ enum BoardMode {
         turn_interval: Duration,
         notify_interval: Duration,
         players: Vec<u16>,
+
+        /// Skip the turns of players which are offline, as long as
+        /// at least one player is online
+        #[serde(default)]
+        skip_absent: bool,
     }
 }
 
@@ -31,6 +59,19 @@ enum BoardMode {
 pub struct BoardManagerConfig {
     mode: BoardMode,
     total_board: Segment,
+
+    /// Maximum number of drawing messages per second and client.
+    /// Messages above the limit are dropped.
+    #[serde(default)]
+    rate_limit: Option<u32>,
+
+    /// Clients we didn't hear from for this long are shown as offline
+    #[serde(default = "default_offline_after")]
+    offline_after: Duration,
+}
+
+fn default_offline_after() -> Duration {
+    Duration::from_secs(10)
 }
 
 #[derive(Deserialize, Debug)]
@@ -41,75 +82,504 @@ pub struct SquaresConfig {
 
 
 #[derive(Deserialize, Debug)]
+#[serde(try_from = "Ranges")]
 pub struct Segment {
     pub x: RangeInclusive<usize>,
     pub y: RangeInclusive<usize>,
 }
 
+/// A `Segment` as written in the config file, before checking that
+/// it fits into the coordinates of the protocol
+#[derive(Deserialize)]
+struct Ranges {
+    x: RangeInclusive<usize>,
+    y: RangeInclusive<usize>,
+}
+
+impl TryFrom<Ranges> for Segment {
+    type Error = String;
+
+    fn try_from(ranges: Ranges) -> Result<Self, String> {
+        for range in [&ranges.x, &ranges.y].iter() {
+            if *range.start() < 1 || *range.end() > usize::from(Coord::MAX) || range.start() > range.end() {
+                return Err(format!("{:?} is not within 1..=255", range));
+            }
+        }
+
+        Ok(Segment {
+            x: ranges.x,
+            y: ranges.y,
+        })
+    }
+}
+
+/// A row or column within a `Segment`, which are checked when
+/// the config is loaded
+fn coord(value: usize) -> Coord {
+    Coord::try_from(value).expect("coordinate outside of the board")
+}
+
+/// Counts the messages of each client within the current second
+struct RateLimiter {
+    limit: Option<u32>,
+    windows: HashMap<u16, (Instant, u32)>,
+}
+
+impl RateLimiter {
+    fn new(limit: Option<u32>) -> Self {
+        RateLimiter {
+            limit,
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Returns false if the client already sent too many messages
+    fn allow(&mut self, source: u16) -> bool {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+
+        let window = self.windows.entry(source).or_insert_with(|| (Instant::now(), 0));
+        if window.0.elapsed() >= Duration::from_secs(1) {
+            *window = (Instant::now(), 0);
+        }
+
+        window.1 += 1;
+        window.1 <= limit
+    }
+}
+
+/// Hands out short addresses to joining boards, always the same
+/// one for each device ID
+struct AddressBook {
+    pool: Vec<u16>,
+    assigned: HashMap<u64, u16>,
+}
+
+impl AddressBook {
+    fn new(pool: Vec<u16>) -> Self {
+        AddressBook {
+            pool,
+            assigned: HashMap::new(),
+        }
+    }
+
+    fn assign(&mut self, device_id: u64) -> Option<u16> {
+        if let Some(addr) = self.assigned.get(&device_id) {
+            return Some(*addr);
+        }
+
+        let taken: Vec<u16> = self.assigned.values().cloned().collect();
+        let addr = *self.pool.iter().find(|addr| !taken.contains(addr))?;
+        self.assigned.insert(device_id, addr);
+
+        Some(addr)
+    }
+}
+
+/// How often to log the link quality of each client
+const LINK_REPORT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Clients received weaker than this are likely to lose messages
+const WEAK_RSSI_DBM: f32 = -90.0;
+
+/// What we know about a client we heard from
+struct Presence {
+    last_seen: Instant,
+    online: bool,
+    heartbeat: Option<Heartbeat>,
+    link: Link,
+}
+
+/// How well the modem received a client since the last link report
+#[derive(Default)]
+struct Link {
+    messages: u32,
+    /// Messages the modem reported a receive quality for
+    measured: u32,
+    rssi_sum: f32,
+    first_path_sum: f32,
+    weakest_rssi: Option<f32>,
+    /// Modem timestamp of the last message, to find gaps
+    last_timestamp_us: Option<u32>,
+    longest_gap_us: u32,
+}
+
+impl Link {
+    fn record(&mut self, rx: &Reception) {
+        self.messages += 1;
+
+        if let Some(last) = self.last_timestamp_us {
+            self.longest_gap_us = self.longest_gap_us.max(rx.timestamp_us.wrapping_sub(last));
+        }
+        self.last_timestamp_us = Some(rx.timestamp_us);
+
+        // The modem failed to read the diagnostics
+        if rx.quality.preamble_count == 0 {
+            return;
+        }
+
+        let rssi = rx.quality.rssi_dbm();
+        self.measured += 1;
+        self.rssi_sum += rssi;
+        self.first_path_sum += rx.quality.first_path_dbm();
+        self.weakest_rssi = Some(self.weakest_rssi.map_or(rssi, |weakest| weakest.min(rssi)));
+    }
+
+    /// Start over for the next report, but keep measuring gaps
+    fn reset(&mut self) {
+        *self = Link {
+            last_timestamp_us: self.last_timestamp_us,
+            .. Link::default()
+        };
+    }
+}
+
+/// Keeps track of which clients are around
+struct Roster {
+    offline_after: Duration,
+    clients: HashMap<u16, Presence>,
+    last_link_report: Instant,
+}
+
+impl Roster {
+    fn new(offline_after: Duration) -> Self {
+        Roster {
+            offline_after,
+            clients: HashMap::new(),
+            last_link_report: Instant::now(),
+        }
+    }
+
+    /// Note that we just heard from `source`
+    fn seen(&mut self, source: u16, rx: &Reception) {
+        let presence = self.clients.entry(source).or_insert_with(|| Presence {
+            last_seen: Instant::now(),
+            online: false,
+            heartbeat: None,
+            link: Link::default(),
+        });
+        presence.last_seen = Instant::now();
+
+        if !presence.online {
+            // Don't count the time it was offline as a gap
+            presence.link.last_timestamp_us = None;
+        }
+        presence.link.record(rx);
+
+        if !presence.online {
+            presence.online = true;
+            info!(target: logging::SERVER, "Client {} is online", source);
+            self.report();
+        }
+    }
+
+    fn heartbeat(&mut self, cmd: HeartbeatCommand) {
+        self.seen(cmd.source, &cmd.rx);
+
+        if let Some(presence) = self.clients.get_mut(&cmd.source) {
+            presence.heartbeat = Some(cmd.heartbeat);
+        }
+    }
+
+    /// Mark clients we didn't hear from in a while as offline
+    fn expire(&mut self) {
+        let mut changed = false;
+
+        for (source, presence) in self.clients.iter_mut() {
+            if presence.online && presence.last_seen.elapsed() > self.offline_after {
+                presence.online = false;
+                changed = true;
+                info!(target: logging::SERVER, "Client {} went offline", source);
+            }
+        }
+
+        if changed {
+            self.report();
+        }
+
+        if self.last_link_report.elapsed() >= LINK_REPORT_INTERVAL {
+            self.report_links();
+            self.last_link_report = Instant::now();
+        }
+    }
+
+    fn is_online(&self, source: u16) -> bool {
+        self.clients.get(&source).map(|p| p.online).unwrap_or(false)
+    }
+
+    /// Log the whole roster
+    fn report(&self) {
+        let mut sources: Vec<&u16> = self.clients.keys().collect();
+        sources.sort();
+
+        let online = self.clients.values().filter(|p| p.online).count();
+        info!(target: logging::SERVER, "Roster: {} of {} clients online", online, sources.len());
+
+        for source in sources {
+            let presence = &self.clients[source];
+            let heartbeat = presence.heartbeat.clone().unwrap_or_default();
+
+            info!(
+                target: logging::SERVER,
+                "  {:5} {:7} last seen {:5.1}s ago, battery: {}, firmware: {}",
+                source,
+                if presence.online { "online" } else { "offline" },
+                presence.last_seen.elapsed().as_secs_f32(),
+                heartbeat.battery_mv.map(|mv| format!("{}mV", mv)).unwrap_or_else(|| "?".into()),
+                heartbeat.firmware.as_ref().map(|fw| fw.as_str()).unwrap_or("?"),
+            );
+        }
+    }
+
+    /// Log how well the modem received each client since the last
+    /// report. A weak or blocked link explains lost messages better
+    /// than a bug in the client.
+    fn report_links(&mut self) {
+        let mut sources: Vec<u16> = self
+            .clients
+            .iter()
+            .filter(|(_, presence)| presence.link.messages != 0)
+            .map(|(source, _)| *source)
+            .collect();
+        if sources.is_empty() {
+            return;
+        }
+        sources.sort();
+
+        info!(
+            target: logging::SERVER,
+            "Link quality over the last {}s:",
+            self.last_link_report.elapsed().as_secs(),
+        );
+
+        for source in sources {
+            let link = &mut self.clients.get_mut(&source).unwrap().link;
+
+            if link.measured == 0 {
+                info!(target: logging::SERVER, "  {:5} {:4} messages, no receive quality reported", source, link.messages);
+            } else {
+                let rssi = link.rssi_sum / link.measured as f32;
+                let first_path = link.first_path_sum / link.measured as f32;
+                let weakest = link.weakest_rssi.unwrap_or(rssi);
+
+                info!(
+                    target: logging::SERVER,
+                    "  {:5} {:4} messages, RSSI {:6.1}dBm (weakest {:6.1}dBm), first path {:6.1}dBm, longest gap {:5.1}s{}{}",
+                    source,
+                    link.messages,
+                    rssi,
+                    weakest,
+                    first_path,
+                    link.longest_gap_us as f32 / 1_000_000.0,
+                    if weakest < WEAK_RSSI_DBM { ", weak" } else { "" },
+                    if rssi - first_path > BLOCKED_PATH_DB { ", blocked?" } else { "" },
+                );
+            }
+
+            link.reset();
+        }
+    }
+}
+
+/// Everything we keep track of per client
+struct Clients {
+    feedback: Feedback,
+    limiter: RateLimiter,
+    addresses: AddressBook,
+    roster: Roster,
+}
+
+/// Don't flood the radio with rejections for a misbehaving client
+const REJECTION_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Sends answers to clients, through the modem
+struct Feedback {
+    prod_rqst: Sender<ModemUartMessages>,
+    last_rejected: HashMap<u16, Instant>,
+}
+
+impl Feedback {
+    fn new(prod_rqst: Sender<ModemUartMessages>) -> Self {
+        Feedback {
+            prod_rqst,
+            last_rejected: HashMap::new(),
+        }
+    }
+
+    fn accepted(&mut self, msg: &DrawCommand) {
+        self.acknowledge(msg, None);
+    }
+
+    /// Clients using sequence numbers get a `Nack`, everyone else
+    /// a `Rejected` for the given cell
+    fn rejected(&mut self, msg: &DrawCommand, reason: RejectReason, cell: Option<Cell>) {
+        if msg.seq.is_some() {
+            self.acknowledge(msg, Some(reason));
+        } else if let Some(cell) = cell {
+            self.notify(msg.source, reason, cell);
+        }
+    }
+
+    /// Send a `Rejected`, unless the client got one very recently
+    fn notify(&mut self, client: u16, reason: RejectReason, cell: Cell) {
+        if let Some(last) = self.last_rejected.get(&client) {
+            if last.elapsed() < REJECTION_INTERVAL {
+                return;
+            }
+        }
+        self.last_rejected.insert(client, Instant::now());
+
+        let reject = RejectCommand {
+            client,
+            rejected: Rejected { reason, cell },
+        };
+        self.send(ModemUartMessages::Reject(reject));
+    }
+
+    fn assign(&mut self, assigned: Assigned) {
+        self.send(ModemUartMessages::Assign(assigned));
+    }
+
+    fn reply(&mut self, client: u16, state: CellState) {
+        self.send(ModemUartMessages::Reply(ReplyCommand { client, state }));
+    }
+
+    fn acknowledge(&mut self, msg: &DrawCommand, rejection: Option<RejectReason>) {
+        if let Some(seq) = msg.seq {
+            let ack = Acknowledge {
+                client: msg.source,
+                seq,
+                rejection,
+            };
+            self.send(ModemUartMessages::Acknowledge(ack));
+        }
+    }
+
+    fn send(&mut self, msg: ModemUartMessages) {
+        if self.prod_rqst.send(msg).is_err() {
+            error!(target: logging::SERVER, "Failed to queue feedback for the modem");
+        }
+    }
+}
+
+/// What the squares server currently shows, as far as we know
+struct Framebuffer {
+    columns: usize,
+    rows: usize,
+    colors: Vec<Option<Color>>,
+}
+
+impl Framebuffer {
+    fn new(board: &Segment) -> Self {
+        let (columns, rows) = (*board.x.end(), *board.y.end());
+
+        Framebuffer {
+            columns,
+            rows,
+            colors: vec![None; columns * rows],
+        }
+    }
+
+    fn index(&self, column: usize, row: usize) -> Option<usize> {
+        if (1..=self.columns).contains(&column) && (1..=self.rows).contains(&row) {
+            Some((row - 1) * self.columns + (column - 1))
+        } else {
+            None
+        }
+    }
+
+    fn set(&mut self, cell: &Cell) {
+        if let Some(idx) = self.index(usize::from(cell.column), usize::from(cell.row)) {
+            self.colors[idx] = Some(Color { red: cell.red, green: cell.green, blue: cell.blue });
+        }
+    }
+
+    fn get(&self, column: usize, row: usize) -> Option<Color> {
+        self.colors[self.index(column, row)?]
+    }
+}
+
 fn drawing(
     mut client: reqwest::Client,
     cell_endpoint: &str,
-    cons_cmds: Receiver<CellCommand>,
+    cons_cmds: Receiver<Request>,
+    mut clients: Clients,
     board: &Segment,
     clear_interval: Duration,
     parts: Option<&Partitions>,
 ) -> Result<(), ()>
 {
     let mut last_start = Instant::now();
+    let mut framebuffer = Framebuffer::new(board);
 
     loop {
         while last_start.elapsed() < clear_interval {
+            clients.roster.expire();
+
             let msg = match cons_cmds.recv_timeout(Duration::from_millis(100)) {
                 Ok(msg) => Ok(msg),
                 Err(RecvTimeoutError::Timeout) => continue,
                 Err(e) => {
-                    eprintln!("cons_cmds receive error! {:?}", e);
+                    error!(target: logging::SERVER, "cons_cmds receive error! {:?}", e);
                     Err(())
                 }
             }?;
 
-            if let Ok((x, y)) = validate_and_remap(board, parts, &msg) {
-                let req = client
-                    .post(cell_endpoint)
-                    .json(&Cell {
-                        column: x,
-                        row: y,
-                        .. msg.cell
-                    })
-                    .send();
-
-                if let Err(e) = req {
-                    eprintln!("post_err: {:?}", e);
+            let msg = match msg {
+                Request::Draw(msg) => msg,
+                Request::Query(query) => {
+                    clients.roster.seen(query.source, &query.rx);
+                    answer(&mut clients.feedback, &framebuffer, board, parts, &query);
+                    continue;
                 }
-            } else {
-                eprintln!("Out of range: {:?}", msg);
+                Request::Join(join) => {
+                    assign(&mut clients, board, parts, join);
+                    continue;
+                }
+                Request::Heartbeat(heartbeat) => {
+                    clients.roster.heartbeat(heartbeat);
+                    continue;
+                }
+            };
+
+            clients.roster.seen(msg.source, &msg.rx);
+
+            if !clients.limiter.allow(msg.source) {
+                warn!(target: logging::SERVER, "Rate limited: {}", msg.source);
+                clients.feedback.rejected(&msg, RejectReason::RateLimited, msg.shape.cells().next());
+                continue;
             }
 
+            let expanded = expand(board, parts, &msg);
+
+            for cell in expanded.cells.iter() {
+                post_cell(&mut client, cell_endpoint, &mut framebuffer, cell);
+            }
 
+            report(&mut clients.feedback, &msg, &expanded);
         }
 
-        clear_map(*board.x.end(), *board.y.end(), &mut client, cell_endpoint);
+        clear_map(*board.x.end(), *board.y.end(), &mut client, cell_endpoint, &mut framebuffer);
         last_start = Instant::now();
     }
 }
 
-struct Color {
-    red: u8,
-    green: u8,
-    blue: u8,
-}
-
 fn turns(
     players: &Vec<u16>,
     board: &Segment,
     mut client: reqwest::Client,
     cell_endpoint: &str,
-    cons_cmds: Receiver<CellCommand>,
+    cons_cmds: Receiver<Request>,
     prod_rqst: Sender<ModemUartMessages>,
+    mut clients: Clients,
     turn_interval: Duration,
     notify_interval: Duration,
+    skip_absent: bool,
 ) -> Result<(), ()> {
     let mut boards: HashMap<u16, Vec<Vec<Color>>> = HashMap::new();
+    let mut framebuffer = Framebuffer::new(board);
     let mut rng = rand::thread_rng();
 
     // Initialize each board with random colors
@@ -132,25 +602,33 @@ fn turns(
     }
 
     for player in players.iter().cycle() {
+        clients.roster.expire();
+
+        if skip_absent
+            && !clients.roster.is_online(*player)
+            && players.iter().any(|p| clients.roster.is_online(*p))
+        {
+            debug!(target: logging::SERVER, "Skipping player {}, who is offline", player);
+            continue;
+        }
+
         let start_turn = Instant::now();
 
         // Send announcement
         prod_rqst.send(ModemUartMessages::AnnounceTurn(*player)).unwrap();
         let mut last_announce = Instant::now();
 
-        println!("");
-        println!("");
-        println!("******************************");
-        println!("* PLAYER {}, START!", player);
-        println!("******************************");
-        println!("");
-        println!("");
+        info!(target: logging::SERVER, "******************************");
+        info!(target: logging::SERVER, "* PLAYER {}, START!", player);
+        info!(target: logging::SERVER, "******************************");
 
         // Restore board
-        set_map(boards.get(player).unwrap(), &mut client, cell_endpoint);
+        set_map(boards.get(player).unwrap(), &mut client, cell_endpoint, &mut framebuffer);
 
         // Process messages for decided time
         while start_turn.elapsed() < turn_interval {
+            clients.roster.expire();
+
             if last_announce.elapsed() > notify_interval {
                 prod_rqst.send(ModemUartMessages::AnnounceTurn(*player)).unwrap();
                 last_announce = Instant::now();
@@ -160,54 +638,87 @@ fn turns(
                 Ok(msg) => Ok(msg),
                 Err(RecvTimeoutError::Timeout) => continue,
                 Err(e) => {
-                    eprintln!("cons_cmds receive error! {:?}", e);
+                    error!(target: logging::SERVER, "cons_cmds receive error! {:?}", e);
                     Err(())
                 }
             }.unwrap();
 
+            let msg = match msg {
+                Request::Draw(msg) => msg,
+                Request::Query(query) => {
+                    clients.roster.seen(query.source, &query.rx);
+                    answer(&mut clients.feedback, &framebuffer, board, None, &query);
+                    continue;
+                }
+                Request::Join(join) => {
+                    assign(&mut clients, board, None, join);
+                    continue;
+                }
+                Request::Heartbeat(heartbeat) => {
+                    clients.roster.heartbeat(heartbeat);
+                    continue;
+                }
+            };
+
+            clients.roster.seen(msg.source, &msg.rx);
+
+            if !clients.limiter.allow(msg.source) {
+                warn!(target: logging::SERVER, "Rate limited: {}", msg.source);
+                clients.feedback.rejected(&msg, RejectReason::RateLimited, msg.shape.cells().next());
+                continue;
+            }
+
             if msg.source != *player {
-                eprintln!("Player {} sent out of turn!", msg.source);
+                warn!(target: logging::SERVER, "Player {} sent out of turn!", msg.source);
+                clients.feedback.rejected(&msg, RejectReason::NotYourTurn, msg.shape.cells().next());
                 continue;
             }
 
-            if let Ok((x, y)) = validate_and_remap(board, None, &msg) {
-                // We know that the range is valid for the board
-                boards.get_mut(player).unwrap()[y-1][x-1] = Color { red: msg.cell.red, green: msg.cell.green, blue: msg.cell.blue };
+            let expanded = expand(board, None, &msg);
 
-                let req = client
-                    .post(cell_endpoint)
-                    .json(&Cell {
-                        column: x,
-                        row: y,
-                        .. msg.cell
-                    })
-                    .send();
+            for cell in expanded.cells.iter() {
+                // We know that the range is valid for the board
+                boards.get_mut(player).unwrap()[usize::from(cell.row) - 1][usize::from(cell.column) - 1] = Color { red: cell.red, green: cell.green, blue: cell.blue };
 
-                if let Err(e) = req {
-                    eprintln!("post_err: {:?}", e);
-                }
-            } else {
-                eprintln!("Out of range: {:?}", msg);
+                post_cell(&mut client, cell_endpoint, &mut framebuffer, cell);
             }
+
+            report(&mut clients.feedback, &msg, &expanded);
         }
     }
 
     Ok(())
 }
 
-fn set_map(y_x: &Vec<Vec<Color>>, client: &mut reqwest::Client, cell_endpoint: &str) {
+fn post_cell(client: &mut reqwest::Client, cell_endpoint: &str, framebuffer: &mut Framebuffer, cell: &Cell) {
+    framebuffer.set(cell);
+
+    let req = client
+        .post(cell_endpoint)
+        .json(cell)
+        .send();
+
+    if let Err(e) = req {
+        warn!(target: logging::SERVER, "post_err: {:?}", e);
+    }
+}
+
+fn set_map(y_x: &Vec<Vec<Color>>, client: &mut reqwest::Client, cell_endpoint: &str, framebuffer: &mut Framebuffer) {
     for (i, y) in y_x.iter().enumerate() {
         for (j, x) in y.iter().enumerate() {
+            let cell = Cell {
+                column: coord(j + 1),
+                row: coord(i + 1),
+                red: x.red,
+                green: x.green,
+                blue: x.blue,
+            };
+            framebuffer.set(&cell);
+
             'retry: for _ in 0..3 {
                 let req = client
                     .post(cell_endpoint)
-                    .json(&Cell {
-                        column: j + 1,
-                        row: i + 1,
-                        red: x.red,
-                        green: x.green,
-                        blue: x.blue,
-                    })
+                    .json(&cell)
                     .send();
 
                 if req.is_ok() {
@@ -218,7 +729,7 @@ fn set_map(y_x: &Vec<Vec<Color>>, client: &mut reqwest::Client, cell_endpoint: &
     }
 }
 
-fn clear_map(x_max: usize, y_max: usize, client: &mut reqwest::Client, cell_endpoint: &str) {
+fn clear_map(x_max: usize, y_max: usize, client: &mut reqwest::Client, cell_endpoint: &str, framebuffer: &mut Framebuffer) {
     let mut rng = rand::thread_rng();
 
     // Time to clear the screen. Pick a muted color, update all pixels
@@ -228,16 +739,19 @@ fn clear_map(x_max: usize, y_max: usize, client: &mut reqwest::Client, cell_endp
 
     for x in 1..=x_max {
         for y in 1..=y_max {
+            let cell = Cell {
+                column: coord(x),
+                row: coord(y),
+                red: red,
+                green: grn,
+                blue: blu,
+            };
+            framebuffer.set(&cell);
+
             'retry: for _ in 0..3 {
                 let req = client
                     .post(cell_endpoint)
-                    .json(&Cell {
-                        column: x,
-                        row: y,
-                        red: red,
-                        green: grn,
-                        blue: blu,
-                    })
+                    .json(&cell)
                     .send();
 
                 if req.is_ok() {
@@ -251,20 +765,37 @@ fn clear_map(x_max: usize, y_max: usize, client: &mut reqwest::Client, cell_endp
 pub fn board_mgr_task(
     cfg_sq: &SquaresConfig,
     cfg_bd: &BoardManagerConfig,
-    cons_cmds: Receiver<CellCommand>,
+    cons_cmds: Receiver<Request>,
     prod_rqst: Sender<ModemUartMessages>,
 ) -> Result<(), ()>
 {
     let client = reqwest::Client::new();
     let cell_endpoint: &str = &format!("{}:{}/cell", cfg_sq.host, cfg_sq.port);
-
     use BoardMode::*;
+    let pool = match cfg_bd.mode {
+        FreeDraw { .. } => FREE_DRAW_ADDRESSES.collect(),
+        Partitioned { ref partitions, .. } => {
+            let mut pool: Vec<u16> = partitions.keys().cloned().collect();
+            pool.sort();
+            pool
+        }
+        RoundRobin { ref players, .. } => players.clone(),
+    };
+
+    let clients = Clients {
+        feedback: Feedback::new(prod_rqst.clone()),
+        limiter: RateLimiter::new(cfg_bd.rate_limit),
+        addresses: AddressBook::new(pool),
+        roster: Roster::new(cfg_bd.offline_after),
+    };
+
     match cfg_bd.mode {
         FreeDraw { clear_interval } => {
             drawing(
                 client,
                 cell_endpoint,
                 cons_cmds,
+                clients,
                 &cfg_bd.total_board,
                 clear_interval,
                 None,
@@ -275,12 +806,13 @@ pub fn board_mgr_task(
                 client,
                 cell_endpoint,
                 cons_cmds,
+                clients,
                 &cfg_bd.total_board,
                 clear_interval,
                 Some(partitions),
             )
         }
-        RoundRobin { turn_interval, notify_interval, ref players } => {
+        RoundRobin { turn_interval, notify_interval, ref players, skip_absent } => {
             turns(
                 players,
                 &cfg_bd.total_board,
@@ -288,26 +820,193 @@ pub fn board_mgr_task(
                 cell_endpoint,
                 cons_cmds,
                 prod_rqst,
+                clients,
                 turn_interval,
                 notify_interval,
+                skip_absent,
             )
         },
     }
 }
 
-fn validate_and_remap(board: &Segment, partitions: Option<&Partitions>, msg: &CellCommand) -> Result<(usize, usize), ()> {
+/// The cells covered by a drawing command
+struct Expanded {
+    /// Cells to draw, in board coordinates
+    cells: Vec<Cell>,
+    /// Number of cells outside of the sender's area
+    dropped: usize,
+    /// The first of the dropped cells, as sent by the client
+    first_dropped: Option<Cell>,
+}
+
+/// Expand a drawing command into the cells it covers. Cells outside
+/// of the sender's area are dropped.
+fn expand(board: &Segment, partitions: Option<&Partitions>, msg: &DrawCommand) -> Expanded {
+    let (columns, rows) = match partitions.and_then(|parts| parts.get(&msg.source)) {
+        Some(part) => (
+            1 + part.x.end() - part.x.start(),
+            1 + part.y.end() - part.y.start(),
+        ),
+        None => (*board.x.end(), *board.y.end()),
+    };
+
+    let mut shape = msg.shape.clone();
+    shape.clip(rows, columns);
+
+    let mut expanded = Expanded {
+        cells: vec![],
+        dropped: 0,
+        first_dropped: None,
+    };
+
+    // Lines are not clipped, don't let a huge one stall the server
+    let limit = match shape {
+        Shape::Line(_) => rows * columns,
+        _ => usize::MAX,
+    };
+
+    for cell in shape.cells().take(limit) {
+        let cmd = CellCommand {
+            source: msg.source,
+            dest: msg.dest,
+            rx: msg.rx,
+            cell,
+        };
+
+        match validate_and_remap(board, partitions, &cmd) {
+            Ok((x, y)) => expanded.cells.push(Cell {
+                column: x,
+                row: y,
+                .. cell
+            }),
+            Err(()) => {
+                expanded.dropped += 1;
+                expanded.first_dropped = expanded.first_dropped.or(Some(cell));
+            }
+        }
+    }
+
+    expanded
+}
+
+/// Log dropped cells, and tell the client how its message was handled
+fn report(feedback: &mut Feedback, msg: &DrawCommand, expanded: &Expanded) {
+    if expanded.dropped != 0 {
+        warn!(target: logging::SERVER, "Out of range: {} cells from {}", expanded.dropped, msg.source);
+    }
+
+    if expanded.cells.is_empty() {
+        let cell = expanded.first_dropped.or_else(|| msg.shape.cells().next());
+        feedback.rejected(msg, RejectReason::OutOfRange, cell);
+    } else {
+        feedback.accepted(msg);
+
+        // Partly drawn, let the client know about the rest
+        if let (None, Some(cell)) = (msg.seq, expanded.first_dropped) {
+            feedback.notify(msg.source, RejectReason::OutOfRange, cell);
+        }
+    }
+}
+
+
+fn region(segment: &Segment) -> Region {
+    let size = |range: &RangeInclusive<usize>| (range.end() + 1).saturating_sub(*range.start()).min(255) as u8;
+
+    Region {
+        row: coord(*segment.y.start()),
+        column: coord(*segment.x.start()),
+        width: size(&segment.x),
+        height: size(&segment.y),
+    }
+}
+
+/// Give a joining board its address, and tell it where to draw
+fn assign(clients: &mut Clients, board: &Segment, partitions: Option<&Partitions>, join: Join) {
+    let short_addr = match clients.addresses.assign(join.device_id) {
+        Some(addr) => addr,
+        None => {
+            warn!(target: logging::SERVER, "No address left for device {:016X}", join.device_id);
+            return;
+        }
+    };
+
+    let segment = partitions
+        .and_then(|parts| parts.get(&short_addr))
+        .unwrap_or(board);
+
+    info!(target: logging::SERVER, "Device {:016X} joined as {}", join.device_id, short_addr);
+
+    clients.feedback.assign(Assigned {
+        device_id: join.device_id,
+        short_addr,
+        partition: region(segment),
+    });
+}
+
+/// Answer a query from the framebuffer, in as many replies as needed
+fn answer(
+    feedback: &mut Feedback,
+    framebuffer: &Framebuffer,
+    board: &Segment,
+    partitions: Option<&Partitions>,
+    query: &QueryCommand,
+) {
+    let region = query.region;
+    let len = region.len().min(MAX_QUERY_CELLS);
+
+    if region.len() > MAX_QUERY_CELLS {
+        warn!(target: logging::SERVER, "Query too large: {:?}, answering {} cells", query, len);
+    }
+
+    for offset in (0..len).step_by(STATE_CELLS) {
+        let mut state = CellState {
+            region,
+            offset: offset as u16,
+            colors: Default::default(),
+        };
+
+        for index in offset..len.min(offset + STATE_CELLS) {
+            let color = region.position(index).and_then(|pos| {
+                let cmd = CellCommand {
+                    source: query.source,
+                    dest: query.dest,
+                    rx: query.rx,
+                    cell: Cell {
+                        row: pos.row,
+                        column: pos.column,
+                        red: 0,
+                        green: 0,
+                        blue: 0,
+                    },
+                };
+                let (x, y) = validate_and_remap(board, partitions, &cmd).ok()?;
+                framebuffer.get(usize::from(x), usize::from(y))
+            });
+
+            // Can't overflow, there are at most STATE_CELLS per reply
+            let _ = state.colors.push(color);
+        }
+
+        feedback.reply(query.source, state);
+    }
+}
+
+fn validate_and_remap(board: &Segment, partitions: Option<&Partitions>, msg: &CellCommand) -> Result<(Coord, Coord), ()> {
+    let column = usize::from(msg.cell.column);
+    let row = usize::from(msg.cell.row);
+
     if let Some(parts) = partitions {
         if let Some(part) = parts.get(&msg.source) {
             let xrange = part.x.end() - part.x.start();
             let yrange = part.y.end() - part.y.start();
 
-            if (msg.cell.column >= 1) &&
-               (msg.cell.column <= (1 + xrange)) &&
-               (msg.cell.row >= 1) &&
-               (msg.cell.row <= (1 + yrange)) {
+            if (column >= 1) &&
+               (column <= (1 + xrange)) &&
+               (row >= 1) &&
+               (row <= (1 + yrange)) {
                 Ok((
-                    msg.cell.column - 1 + part.x.start(),
-                    msg.cell.row    - 1 + part.y.start()
+                    coord(column - 1 + part.x.start()),
+                    coord(row    - 1 + part.y.start())
                 ))
             } else {
                 Err(())
@@ -316,10 +1015,10 @@ fn validate_and_remap(board: &Segment, partitions: Option<&Partitions>, msg: &Ce
             Err(())
         }
     } else {
-        if (board.x.start() <= &msg.cell.column) &&
-           (board.x.end() >= &msg.cell.column) &&
-           (board.y.start() <= &msg.cell.row) &&
-           (board.y.end() >= &msg.cell.row) {
+        if (board.x.start() <= &column) &&
+           (board.x.end() >= &column) &&
+           (board.y.start() <= &row) &&
+           (board.y.end() >= &row) {
             Ok((msg.cell.column, msg.cell.row))
         } else {
             Err(())
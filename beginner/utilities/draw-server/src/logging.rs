@@ -0,0 +1,90 @@
+use std::io::Write;
+
+use flexi_logger::{Cleanup, Criterion, DeferredNow, Duplicate, FlexiLoggerError, Logger, Naming, ReconfigurationHandle};
+use log::Record;
+use serde::Deserialize;
+use serde_json::json;
+
+/// Log target for messages originating in the draw-server itself
+pub const SERVER: &str = "server";
+
+/// Log target for log lines forwarded by the modem firmware
+pub const MODEM: &str = "modem-firmware";
+
+/// Log target for decoded protocol messages and raw binary dumps
+pub const PROTOCOL: &str = "protocol";
+
+#[derive(Deserialize, Debug)]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// A filter in `RUST_LOG` syntax, e.g. `"info,protocol=debug"`.
+    /// If the `RUST_LOG` environment variable is set, it takes precedence.
+    filter: String,
+
+    /// If set, log records are additionally written as JSON lines
+    /// to a rotating log file
+    file: Option<LogFileConfig>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LogFileConfig {
+    directory: String,
+    rotate_bytes: u64,
+    keep_files: usize,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            filter: "info".into(),
+            file: None,
+        }
+    }
+}
+
+/// Start the global logger. The returned handle must be kept alive
+/// for as long as logging is required.
+pub fn init(cfg: &LoggingConfig) -> Result<ReconfigurationHandle, FlexiLoggerError> {
+    let logger = Logger::with_env_or_str(&cfg.filter)
+        .format_for_stderr(text_format);
+
+    match cfg.file {
+        Some(ref file) => {
+            logger
+                .log_to_file()
+                .directory(file.directory.as_str())
+                .suffix("jsonl")
+                .format_for_files(json_format)
+                .rotate(
+                    Criterion::Size(file.rotate_bytes),
+                    Naming::Numbers,
+                    Cleanup::KeepLogFiles(file.keep_files),
+                )
+                .duplicate_to_stderr(Duplicate::All)
+                .start()
+        }
+        None => logger.start(),
+    }
+}
+
+fn text_format(w: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> Result<(), std::io::Error> {
+    write!(
+        w,
+        "{} {:<5} [{}] {}",
+        now.now().format("%H:%M:%S%.3f"),
+        record.level(),
+        record.target(),
+        record.args(),
+    )
+}
+
+fn json_format(w: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> Result<(), std::io::Error> {
+    let line = json!({
+        "ts": now.now().to_rfc3339(),
+        "level": record.level().to_string(),
+        "target": record.target(),
+        "msg": record.args().to_string(),
+    });
+
+    write!(w, "{}", line)
+}
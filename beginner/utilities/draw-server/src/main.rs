@@ -6,22 +6,31 @@ use std::sync::mpsc::channel;
 use std::thread::{spawn};
 
 use modem_comms::modem_task;
-use board_mgr::board_mgr_task;
-use protocol::{CellCommand, ModemUartMessages};
+use board_mgr::{board_mgr_task, Request};
+use protocol::ModemUartMessages;
 
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use std::path::Path;
 use std::fs::File;
+use log::error;
 
 mod modem_comms;
 mod board_mgr;
+mod logging;
+mod pcap;
 
 #[derive(Deserialize, Debug)]
 struct Config {
     serial: SerialConfig,
     squares: board_mgr::SquaresConfig,
     board: board_mgr::BoardManagerConfig,
+    #[serde(default)]
+    logging: logging::LoggingConfig,
+    #[serde(default)]
+    capture: Option<pcap::CaptureConfig>,
+    #[serde(default)]
+    modem: modem_comms::ModemConfig,
 }
 
 #[derive(Deserialize, Debug)]
@@ -33,30 +42,54 @@ struct SerialConfig {
 
 fn main() {
     let config: Config = just_load(Path::new("./draw.ron")).unwrap();
+    let _log_hdl = match logging::init(&config.logging) {
+        Ok(hdl) => hdl,
+        Err(e) => {
+            eprintln!("Failed to start logging. Error: {}", e);
+            ::std::process::exit(1);
+        }
+    };
+
     let mut settings: SerialPortSettings = Default::default();
     settings.timeout = Duration::from_millis(config.serial.timeout_ms);
     settings.baud_rate = config.serial.baudrate;
 
-    let (prod_cmds, cons_cmds) = channel::<CellCommand>();
+    let (prod_cmds, cons_cmds) = channel::<Request>();
     let (prod_rqst, cons_rqst) = channel::<ModemUartMessages>();
 
     let port = match serialport::open_with_settings(&config.serial.port, &settings) {
         Ok(port) => port,
         Err(e) => {
-            eprintln!("Failed to open \"{}\". Error: {}", &config.serial.port, e);
+            error!(target: logging::SERVER, "Failed to open \"{}\". Error: {}", &config.serial.port, e);
             ::std::process::exit(1);
         }
     };
 
+    let capture = match config.capture {
+        Some(ref cfg) => match pcap::PcapWriter::create(cfg) {
+            Ok(writer) => Some(writer),
+            Err(e) => {
+                error!(target: logging::SERVER, "Failed to create capture file {:?}. Error: {}", cfg, e);
+                ::std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let modem_cfg = config.modem;
     let modem_hdl = spawn(move || modem_task(
         port,
         prod_cmds,
         cons_rqst,
+        &modem_cfg,
+        capture,
         )
     );
+    let squares_cfg = config.squares;
+    let board_cfg = config.board;
     let board_hdl = spawn(move || board_mgr_task(
-        &config.squares,
-        &config.board,
+        &squares_cfg,
+        &board_cfg,
         cons_cmds,
         prod_rqst,
         )
@@ -1,24 +1,206 @@
 use serialport::prelude::*;
-use postcard::{from_bytes, to_slice_cobs};
+use postcard::from_bytes;
 use nrf52_bin_logger::LogOnLine;
-use protocol::{ModemUartMessages, CellCommand};
+use protocol::{
+    auth::{self, Key},
+    envelope, wire,
+    capabilities, CheckedFrame, ClientKey, Hello, ModemSettings, ModemStatus, ModemUartMessages,
+    Version, PROTOCOL_VERSION,
+};
+use std::collections::HashMap;
 use std::sync::mpsc::{Sender, Receiver, TryRecvError};
-use chrono::prelude::*;
+use std::time::{Duration, Instant};
+use log::{debug, error, info, log, warn, Level};
+
+use serde::Deserialize;
+
+use crate::board_mgr::Request;
+use crate::logging;
+use crate::pcap::PcapWriter;
+
+/// How long to wait for the modem to answer our `Hello`
+const HELLO_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct ModemConfig {
+    /// Keep going if the modem speaks a different protocol version.
+    /// Expect decoding errors!
+    allow_version_mismatch: bool,
+
+    /// Forward every received frame, including those addressed
+    /// to other devices
+    sniffer: bool,
+
+    /// Radio settings pushed to the modem at startup. If not set,
+    /// the modem keeps its built-in defaults.
+    settings: Option<ModemSettings>,
+
+    /// Keys of the clients, as 32 hex digits, by short address. Frames
+    /// from these addresses are dropped unless signed with the key.
+    keys: HashMap<u16, String>,
+}
 
 struct Modem {
     port: Box<dyn SerialPort>,
     cobs_buf: Vec<u8>,
     since_last_err: usize,
+    capture: Option<PcapWriter>,
+    settings: Option<ModemSettings>,
+    keys: Vec<(u16, Key)>,
+    last_status: ModemStatus,
+    /// Frames from the modem which didn't match their CRC
+    crc_errors: u32,
+
+    /// The modem's answer to the version handshake
+    peer: Option<Hello>,
+    incompatible: bool,
+    needs_setup: bool,
 }
 
 impl Modem {
-    fn process_serial(&mut self) -> Result<Vec<CellCommand>, ()> {
+    fn send(&mut self, msg: &ModemUartMessages) -> Result<(), ()> {
+        debug!(target: logging::PROTOCOL, "TX {}", msg);
+        let bytes = wire::to_uart(msg).map_err(|_| ())?;
+
+        self.port.write(&bytes).map_err(|_| ())?;
+        Ok(())
+    }
+
+    fn handle_hello(&mut self, hello: Hello) {
+        info!(
+            target: logging::SERVER,
+            "Modem firmware v{}, protocol version {}",
+            hello.firmware_version,
+            hello.protocol_version,
+        );
+
+        self.incompatible = hello.protocol_version != PROTOCOL_VERSION;
+        if self.incompatible {
+            error!(
+                target: logging::SERVER,
+                "Modem speaks protocol version {}, but the server expects {}. Please reflash the modem from this checkout!",
+                hello.protocol_version,
+                PROTOCOL_VERSION,
+            );
+        }
+
+        // The modem sends a `Hello` when it (re)boots, and has lost
+        // any settings we pushed to it before
+        self.peer = Some(hello);
+        self.needs_setup = true;
+    }
+
+    /// Whether the modem supports `capability`. Modems which didn't
+    /// answer the handshake are assumed to support everything.
+    fn supports(&self, capability: u32, name: &str) -> bool {
+        match self.peer {
+            Some(ref hello) if !hello.supports(capability) => {
+                warn!(target: logging::SERVER, "Modem firmware does not support {}", name);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Push the configured settings to the modem
+    fn setup(&mut self, config: &ModemConfig) -> Result<(), ()> {
+        if self.supports(capabilities::STATUS, "status reports") {
+            self.send(&ModemUartMessages::GetStatus)?;
+        }
+
+        if self.capture.is_some() && self.supports(capabilities::CAPTURE, "capturing") {
+            info!(target: logging::SERVER, "Capturing radio frames");
+            self.send(&ModemUartMessages::SetCapture(true))?;
+        }
+
+        if let Some(settings) = config.settings {
+            if self.supports(capabilities::CONFIGURE, "runtime configuration") {
+                info!(target: logging::SERVER, "Configuring modem: {:?}", settings);
+                self.send(&ModemUartMessages::Configure(settings))?;
+            }
+        }
+
+        if !self.keys.is_empty() && self.supports(capabilities::AUTH, "signed frames") {
+            info!(target: logging::SERVER, "Sending keys of {} clients to the modem", self.keys.len());
+            for (client, key) in self.keys.clone() {
+                self.send(&ModemUartMessages::SetKey(ClientKey { client, key: Some(key) }))?;
+            }
+        }
+
+        if config.sniffer && self.supports(capabilities::SNIFFER, "sniffer mode") {
+            info!(target: logging::SERVER, "Enabling modem sniffer mode");
+            self.send(&ModemUartMessages::SetSniffer(true))?;
+        }
+
+        Ok(())
+    }
+
+    fn capture_frame(&mut self, frame: &protocol::RadioFrame) {
+        if let Some(ref mut capture) = self.capture {
+            if let Err(e) = capture.write_frame(frame) {
+                error!(target: logging::SERVER, "Failed to write capture: {:?}", e);
+            }
+        }
+    }
+
+    fn report_status(&mut self, status: &ModemStatus) {
+        info!(
+            target: logging::MODEM,
+            "Status: up {}.{:03}s, {} frames received, {} rejected, {} rx errors, {} reassembly timeouts, {} CRC errors, lost {} bytes / {} msgs",
+            status.uptime_ms / 1000,
+            status.uptime_ms % 1000,
+            status.frames_received,
+            status.rejected.total(),
+            status.rx_errors,
+            status.reassembly_timeouts,
+            status.crc_errors,
+            status.lost_bytes,
+            status.lost_msgs,
+        );
+        debug!(target: logging::MODEM, "Rejected frames: {:?}", status.rejected);
+
+        // Counters restart when the modem reboots
+        let prev = if status.uptime_ms >= self.last_status.uptime_ms {
+            self.last_status
+        } else {
+            ModemStatus::default()
+        };
+
+        if status.lost_msgs > prev.lost_msgs {
+            warn!(
+                target: logging::MODEM,
+                "Modem dropped {} messages since the last report",
+                status.lost_msgs - prev.lost_msgs,
+            );
+        }
+
+        if status.crc_errors > prev.crc_errors {
+            warn!(
+                target: logging::MODEM,
+                "Modem dropped {} commands with a CRC mismatch since the last report",
+                status.crc_errors - prev.crc_errors,
+            );
+        }
+
+        if status.rejected.bad_tag > prev.rejected.bad_tag {
+            warn!(
+                target: logging::MODEM,
+                "Modem dropped {} frames with a missing or invalid tag since the last report",
+                status.rejected.bad_tag - prev.rejected.bad_tag,
+            );
+        }
+
+        self.last_status = *status;
+    }
+
+    fn process_serial(&mut self) -> Result<Vec<Request>, ()> {
         let mut buf = [0u8; 1024];
         let buf = match self.port.read(&mut buf) {
             Ok(ct) => &buf[..ct],
             Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => return Ok(vec![]),
             Err(e) => {
-                eprintln!("{:?}", e);
+                error!(target: logging::SERVER, "Serial read failed: {:?}", e);
                 return Err(());
             }
         };
@@ -26,7 +208,7 @@ impl Modem {
         self.push_bytes(buf)
     }
 
-    fn push_bytes(&mut self, mut data: &[u8]) -> Result<Vec<CellCommand>, ()> {
+    fn push_bytes(&mut self, mut data: &[u8]) -> Result<Vec<Request>, ()> {
         let mut resps = vec![];
 
         while let Some(idx) = data.iter().position(|&b| b == 0) {
@@ -36,29 +218,114 @@ impl Modem {
             use LogOnLine::*;
             use ModemUartMessages::*;
             if let Ok(idx) = cobs::decode_in_place(&mut self.cobs_buf) {
-                let decode_result = from_bytes::<LogOnLine<ModemUartMessages>>(&self.cobs_buf[..idx]);
+                let decode_result = decode(&self.cobs_buf[..idx]);
                 if let Ok(ref msg) = decode_result {
                     display(&msg);
                 }
                 match decode_result {
                     Ok(ProtocolMessage(SetCell(desmsg))) =>  {
                         self.since_last_err += 1;
-                        resps.push(desmsg);
+                        resps.push(Request::Draw(desmsg.into()));
+                    }
+                    Ok(ProtocolMessage(Draw(desmsg))) =>  {
+                        self.since_last_err += 1;
+                        resps.push(Request::Draw(desmsg));
+                    }
+                    Ok(ProtocolMessage(Blit(desmsg))) =>  {
+                        self.since_last_err += 1;
+                        resps.push(Request::Draw(desmsg.into()));
+                    }
+                    Ok(ProtocolMessage(Query(query))) =>  {
+                        self.since_last_err += 1;
+                        resps.push(Request::Query(query));
+                    }
+                    Ok(ProtocolMessage(Join(join))) =>  {
+                        self.since_last_err += 1;
+                        resps.push(Request::Join(join));
+                    }
+                    Ok(ProtocolMessage(Heartbeat(heartbeat))) =>  {
+                        self.since_last_err += 1;
+                        resps.push(Request::Heartbeat(heartbeat));
                     }
                     Ok(ProtocolMessage(Loopback(val))) =>  {
                         self.since_last_err += 1;
-                        eprintln!("Got Loopback! Good: {}", val == 0x4242_4242);
+                        info!(target: logging::SERVER, "Got Loopback! Good: {}", val == 0x4242_4242);
+                    }
+                    Ok(ProtocolMessage(Pong(pong))) =>  {
+                        self.since_last_err += 1;
+                        info!(
+                            target: logging::SERVER,
+                            "Pong from {}: {}.{:03}ms, RSSI {:.1}dBm, first path {:.1}dBm",
+                            pong.source,
+                            pong.rtt_us / 1000,
+                            pong.rtt_us % 1000,
+                            pong.quality.rssi_dbm(),
+                            pong.quality.first_path_dbm(),
+                        );
+                    }
+                    Ok(ProtocolMessage(RadioFrame(frame))) => {
+                        self.since_last_err += 1;
+                        self.capture_frame(&frame);
+                    }
+                    Ok(ProtocolMessage(Sniffed(sniffed))) => {
+                        self.since_last_err += 1;
+                        if let Some(rejection) = sniffed.rejection {
+                            info!(
+                                target: logging::PROTOCOL,
+                                "Sniffed frame {:?} -> {:?} rejected: {}",
+                                sniffed.frame.header.source,
+                                sniffed.frame.header.destination,
+                                rejection,
+                            );
+                        }
+                        self.capture_frame(&sniffed.frame);
+                    }
+                    Ok(ProtocolMessage(Hello(hello))) => {
+                        self.since_last_err += 1;
+                        self.handle_hello(hello);
+                    }
+                    Ok(ProtocolMessage(Status(status))) => {
+                        self.since_last_err += 1;
+                        self.report_status(&status);
+                    }
+                    Ok(ProtocolMessage(Configured(applied))) => {
+                        self.since_last_err += 1;
+                        info!(target: logging::SERVER, "Modem settings: {:?}", applied);
+                        match self.settings {
+                            Some(ref requested) if *requested != applied => {
+                                warn!(
+                                    target: logging::SERVER,
+                                    "Modem did not apply all requested settings! Requested: {:?}",
+                                    requested,
+                                );
+                            }
+                            _ => {}
+                        }
                     }
                     Ok(_other) => {
                         self.since_last_err += 1;
                     },
-                    Err(e) => {
-                        eprintln!("bad_decode: {:?}, since_last: {}", e, self.since_last_err);
+                    Err(FrameError::Crc) => {
+                        self.crc_errors += 1;
+                        warn!(
+                            target: logging::PROTOCOL,
+                            "CRC mismatch, {} so far, since_last: {}",
+                            self.crc_errors,
+                            self.since_last_err,
+                        );
+                        self.since_last_err = 0;
+                    }
+                    Err(FrameError::Unsupported(index)) => {
+                        self.since_last_err += 1;
+                        warn!(target: logging::PROTOCOL, "Skipped unsupported message type {} from the modem", index);
+                    }
+                    Err(FrameError::Decode(e)) => {
+                        warn!(target: logging::PROTOCOL, "bad_decode: {:?}, since_last: {}", e, self.since_last_err);
                         self.since_last_err = 0;
                     }
                 }
             } else {
-                eprintln!("Bad Cobs, since_last: {}", self.since_last_err);
+                warn!(target: logging::PROTOCOL, "Bad Cobs, since_last: {}", self.since_last_err);
                 self.since_last_err = 0;
             }
 
@@ -74,29 +341,82 @@ impl Modem {
 
 pub fn modem_task(
     port: Box<dyn SerialPort>,
-    prod_cmds: Sender<CellCommand>,
+    prod_cmds: Sender<Request>,
     cons_rqst: Receiver<ModemUartMessages>,
+    config: &ModemConfig,
+    capture: Option<PcapWriter>,
 ) -> Result<(), ()>
 {
-    println!("Receiving data on {} at {} baud:", port.name().unwrap(), port.baud_rate().unwrap());
+    info!(
+        target: logging::SERVER,
+        "Receiving data on {} at {} baud",
+        port.name().unwrap(),
+        port.baud_rate().unwrap(),
+    );
+
+    let mut keys = Vec::new();
+    for (client, hex) in config.keys.iter() {
+        match auth::parse_key(hex) {
+            Some(key) => keys.push((*client, key)),
+            None => {
+                error!(target: logging::SERVER, "Key of client {} is not 32 hex digits", client);
+                return Err(());
+            }
+        }
+    }
+    keys.sort();
 
     let mut modem = Modem {
         port,
         cobs_buf: vec![],
         since_last_err: 0,
+        capture,
+        settings: config.settings,
+        keys,
+        last_status: ModemStatus::default(),
+        crc_errors: 0,
+        peer: None,
+        incompatible: false,
+        needs_setup: false,
     };
 
+    modem.send(&ModemUartMessages::Hello(Hello {
+        protocol_version: PROTOCOL_VERSION,
+        firmware_version: Version {
+            major: env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+            minor: env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+            patch: env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+        },
+        capabilities: 0,
+    }))?;
+    let hello_sent = Instant::now();
+    let mut hello_timed_out = false;
+
     loop {
+        if modem.incompatible && !config.allow_version_mismatch {
+            error!(
+                target: logging::SERVER,
+                "Refusing to talk to an incompatible modem. Set `allow_version_mismatch: true` to continue anyway.",
+            );
+            ::std::process::exit(1);
+        }
+
+        if modem.peer.is_none() && !hello_timed_out && hello_sent.elapsed() > HELLO_TIMEOUT {
+            warn!(
+                target: logging::SERVER,
+                "Modem did not answer the version handshake, it probably runs outdated firmware",
+            );
+            hello_timed_out = true;
+            modem.needs_setup = true;
+        }
+
+        if modem.needs_setup {
+            modem.needs_setup = false;
+            modem.setup(config)?;
+        }
+
         match cons_rqst.try_recv() {
-            Ok(msg) => {
-                let mut buf = [0u8; 1024];
-                let buf2 = to_slice_cobs(
-                    &msg,
-                    &mut buf
-                ).map_err(|_| ())?;
-
-                modem.port.write(&buf2).map_err(|_| ())?;
-            }
+            Ok(msg) => modem.send(&msg)?,
             Err(TryRecvError::Empty) => {},
             Err(TryRecvError::Disconnected) => return Err(()),
         };
@@ -109,36 +429,56 @@ pub fn modem_task(
     }
 }
 
+/// Why a frame from the modem was dropped
+enum FrameError {
+    Decode(postcard::Error),
+    /// The `CheckedFrame` didn't match its CRC
+    Crc,
+    /// A message type added in a newer protocol version
+    Unsupported(u32),
+}
+
+/// Decode a frame from the modem, checking the CRC of protocol messages
+fn decode(bytes: &[u8]) -> Result<LogOnLine<ModemUartMessages>, FrameError> {
+    use LogOnLine::*;
+
+    let line = from_bytes::<LogOnLine<CheckedFrame>>(bytes).map_err(FrameError::Decode)?;
+
+    Ok(match line {
+        Log(log) => Log(log),
+        Warn(log) => Warn(log),
+        Error(log) => Error(log),
+        BinaryRaw(log) => BinaryRaw(log),
+        ProtocolMessage(frame) => {
+            let bytes = frame.check().map_err(|_| FrameError::Crc)?;
+            ProtocolMessage(from_bytes(bytes).map_err(|e| {
+                match envelope::unsupported(bytes, ModemUartMessages::VARIANTS) {
+                    Some(index) => FrameError::Unsupported(index),
+                    None => FrameError::Decode(e),
+                }
+            })?)
+        }
+    })
+}
+
 fn display(msg: &LogOnLine<ModemUartMessages>) {
     match msg {
-        LogOnLine::Log(log) => {
-            eprintln!("{}", prefixed_lines(log, "LOG"));
-        }
-        LogOnLine::Warn(log) => {
-            eprintln!("{}", prefixed_lines(log, "WRN"));
-        }
-        LogOnLine::Error(log) => {
-            eprintln!("{}", prefixed_lines(log, "ERR"));
-        }
+        LogOnLine::Log(log) => modem_lines(Level::Info, log),
+        LogOnLine::Warn(log) => modem_lines(Level::Warn, log),
+        LogOnLine::Error(log) => modem_lines(Level::Error, log),
         LogOnLine::BinaryRaw(log) => {
-            eprintln!("{}", prefixed_lines(&format!("{:02X?}", log), "BIN"));
+            debug!(target: logging::PROTOCOL, "BIN {:02X?}", log);
         }
         LogOnLine::ProtocolMessage(proto_msg) => {
-            println!("{}", prefixed_lines(&format!("{:#?}", proto_msg), "RX"));
+            debug!(target: logging::PROTOCOL, "RX {}", proto_msg);
         }
     }
 }
 
-fn prefixed_lines(st: &str, msg: &str) -> String {
-    let mut out = String::new();
-    out += &format!("{:?}\n", Local::now());
+/// Modem log messages may span several lines, log each of them
+/// as a separate record
+fn modem_lines(level: Level, st: &str) {
     st.lines().for_each(|line| {
-        out += &format!(
-            " => {}: {}\n",
-            msg,
-            line
-        );
+        log!(target: logging::MODEM, level, "{}", line);
     });
-    out.truncate(out.trim_end().len());
-    out
 }
@@ -0,0 +1,60 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use protocol::RadioFrame;
+use serde::Deserialize;
+
+/// IEEE 802.15.4 frames, without the trailing FCS. The modem does not
+/// forward the checksum, as the radio has already verified it.
+const LINKTYPE_IEEE802_15_4_NOFCS: u32 = 230;
+
+#[derive(Deserialize, Debug)]
+pub struct CaptureConfig {
+    /// Path of the pcap file. An existing file will be overwritten.
+    path: String,
+}
+
+/// Writes received radio frames to a pcap file, which can be
+/// opened with Wireshark
+pub struct PcapWriter {
+    out: BufWriter<File>,
+}
+
+impl PcapWriter {
+    pub fn create(cfg: &CaptureConfig) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(Path::new(&cfg.path))?);
+
+        // Global header
+        out.write_all(&0xA1B2_C3D4u32.to_le_bytes())?; // magic
+        out.write_all(&2u16.to_le_bytes())?;           // version major
+        out.write_all(&4u16.to_le_bytes())?;           // version minor
+        out.write_all(&0i32.to_le_bytes())?;           // thiszone
+        out.write_all(&0u32.to_le_bytes())?;           // sigfigs
+        out.write_all(&127u32.to_le_bytes())?;         // snaplen
+        out.write_all(&LINKTYPE_IEEE802_15_4_NOFCS.to_le_bytes())?;
+        out.flush()?;
+
+        Ok(PcapWriter { out })
+    }
+
+    /// Append a frame, timestamped with the time it was received
+    /// by the host
+    pub fn write_frame(&mut self, frame: &RadioFrame) -> io::Result<()> {
+        let data = frame.to_mac_frame();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        self.out.write_all(&(now.as_secs() as u32).to_le_bytes())?;
+        self.out.write_all(&now.subsec_micros().to_le_bytes())?;
+        self.out.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.out.write_all(&(data.len() as u32).to_le_bytes())?;
+        self.out.write_all(&data)?;
+
+        // Flush every frame, so the capture is usable even if the
+        // server is killed
+        self.out.flush()
+    }
+}
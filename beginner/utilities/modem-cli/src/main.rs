@@ -0,0 +1,583 @@
+//! Talk to the draw-modem directly, without the draw-server
+//!
+//! Usage: modem-cli [--port PORT] [--baud BAUD] COMMAND
+//!
+//! * `ping [COUNT]` - Send `Loopback`s, and report their round trip time
+//! * `announce ADDR` - Announce the turn of a client
+//! * `settings KEY=VALUE...` - Change the modem settings, e.g. `channel=5`
+//! * `stream [--sniff]` - Print every frame the modem receives
+//! * `throughput [COUNT] [--window N]` - Benchmark the serial link with `LoadLoopBack`s
+//! * `radioping ADDR... [--count N]` - Ping clients over the radio, and report their reception
+//! * `send MESSAGE` - Send any message, in the text form of `protocol::text`
+
+use std::collections::VecDeque;
+use std::env;
+use std::io::{self, ErrorKind, Read, Write};
+use std::process;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use nrf52_bin_logger::LogOnLine;
+use postcard::from_bytes_cobs;
+use protocol::{
+    capabilities, wire, CheckedFrame, Hello, ModemUartMessages, RxQuality, Version,
+    BLOCKED_PATH_DB, PROTOCOL_VERSION,
+};
+use serialport::prelude::*;
+
+const USAGE: &str = "Usage: modem-cli [--port PORT] [--baud BAUD] COMMAND
+
+Commands:
+    ping [COUNT]            Send loopbacks, and report their round trip time
+    announce ADDR           Announce the turn of a client
+    settings KEY=VALUE...   Change the modem settings, e.g. channel=5
+    stream [--sniff]        Print every frame the modem receives
+    throughput [COUNT] [--window N]
+                            Benchmark the serial link with LoadLoopBacks
+    radioping ADDR... [--count N]
+                            Ping clients over the radio, e.g. 1-16, and
+                            report their round trip time and reception
+    send MESSAGE            Send any message, e.g. `send getstatus`";
+
+/// How long to wait for an answer from the modem. It only handles
+/// commands between its receive windows, so allow for a few of them.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct Modem {
+    port: Box<dyn SerialPort>,
+    cobs_buf: Vec<u8>,
+    received: VecDeque<ModemUartMessages>,
+    peer: Option<Hello>,
+}
+
+impl Modem {
+    fn open(port: &str, baud_rate: u32) -> Result<Self, String> {
+        let settings = SerialPortSettings {
+            baud_rate,
+            timeout: Duration::from_millis(10),
+            ..Default::default()
+        };
+
+        let port = serialport::open_with_settings(port, &settings)
+            .map_err(|e| format!("Failed to open \"{}\". Error: {}", port, e))?;
+
+        Ok(Modem {
+            port,
+            cobs_buf: vec![],
+            received: VecDeque::new(),
+            peer: None,
+        })
+    }
+
+    fn send(&mut self, msg: &ModemUartMessages) -> Result<(), String> {
+        let bytes = wire::to_uart(msg).map_err(|e| format!("Failed to encode {}: {}", msg, e))?;
+        self.port
+            .write_all(&bytes)
+            .map_err(|e| format!("Failed to write to the modem: {}", e))
+    }
+
+    /// The next message from the modem, or `None` once `deadline`
+    /// passed. Log messages of the modem are printed to stderr.
+    fn recv(&mut self, deadline: Instant) -> Result<Option<ModemUartMessages>, String> {
+        let mut buf = [0u8; 1024];
+
+        loop {
+            if let Some(msg) = self.received.pop_front() {
+                return Ok(Some(msg));
+            }
+            if Instant::now() >= deadline {
+                return Ok(None);
+            }
+
+            match self.port.read(&mut buf) {
+                Ok(len) => self.push_bytes(&buf[..len]),
+                Err(ref e) if e.kind() == ErrorKind::TimedOut => {}
+                Err(e) => return Err(format!("Serial read failed: {}", e)),
+            }
+        }
+    }
+
+    /// Wait for the first message accepted by `matches`
+    fn expect<T, F>(&mut self, timeout: Duration, mut matches: F) -> Result<Option<T>, String>
+    where
+        F: FnMut(ModemUartMessages) -> Option<T>,
+    {
+        let deadline = Instant::now() + timeout;
+        while let Some(msg) = self.recv(deadline)? {
+            if let Some(found) = matches(msg) {
+                return Ok(Some(found));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn push_bytes(&mut self, mut data: &[u8]) {
+        while let Some(idx) = data.iter().position(|&b| b == 0) {
+            let (end, rest) = data.split_at(idx + 1);
+            self.cobs_buf.extend_from_slice(end);
+
+            match from_bytes_cobs::<LogOnLine<CheckedFrame>>(&mut self.cobs_buf) {
+                Ok(LogOnLine::Log(log)) => eprintln!("modem: {}", log),
+                Ok(LogOnLine::Warn(log)) => eprintln!("modem warning: {}", log),
+                Ok(LogOnLine::Error(log)) => eprintln!("modem error: {}", log),
+                Ok(LogOnLine::BinaryRaw(bin)) => eprintln!("modem binary: {:02x?}", bin),
+                Ok(LogOnLine::ProtocolMessage(frame)) => match wire::from_checked(&frame) {
+                    Ok(msg) => self.received.push_back(msg),
+                    Err(e) => eprintln!("Dropped a message from the modem: {}", e),
+                },
+                Err(e) => eprintln!("Failed to decode a frame from the modem: {:?}", e),
+            }
+
+            self.cobs_buf.clear();
+            data = rest;
+        }
+
+        self.cobs_buf.extend_from_slice(data);
+    }
+
+    /// Exchange `Hello`s, and warn if the modem doesn't speak our protocol
+    fn handshake(&mut self) -> Result<(), String> {
+        self.send(&ModemUartMessages::Hello(Hello {
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: Version {
+                major: env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+                minor: env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+                patch: env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+            },
+            capabilities: 0,
+        }))?;
+
+        self.peer = self.expect(REPLY_TIMEOUT, |msg| match msg {
+            ModemUartMessages::Hello(hello) => Some(hello),
+            _ => None,
+        })?;
+
+        match self.peer {
+            Some(hello) => {
+                eprintln!(
+                    "Modem firmware v{}, protocol version {}",
+                    hello.firmware_version, hello.protocol_version,
+                );
+                if hello.protocol_version != PROTOCOL_VERSION {
+                    eprintln!(
+                        "Warning: this tool speaks protocol version {}, most messages will be garbled",
+                        PROTOCOL_VERSION,
+                    );
+                }
+            }
+            None => eprintln!("Warning: the modem did not answer, its firmware may predate the handshake"),
+        }
+
+        Ok(())
+    }
+
+    /// Whether the modem reported support for a feature. Modems which
+    /// didn't answer the handshake get the benefit of the doubt.
+    fn supports(&self, capability: u32, feature: &str) -> bool {
+        match self.peer {
+            Some(hello) if hello.capabilities & capability == 0 => {
+                eprintln!("Warning: the modem firmware does not support {}", feature);
+                false
+            }
+            _ => true,
+        }
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1).peekable();
+    let mut port = String::from("/dev/ttyACM0");
+    let mut baud_rate = 115_200;
+
+    loop {
+        match args.peek().map(String::as_str) {
+            Some("--port") => {
+                args.next();
+                port = args.next().unwrap_or_else(|| usage("--port needs a value"));
+            }
+            Some("--baud") => {
+                args.next();
+                baud_rate = args
+                    .next()
+                    .and_then(|baud| baud.parse().ok())
+                    .unwrap_or_else(|| usage("--baud needs a number"));
+            }
+            _ => break,
+        }
+    }
+
+    let command = args.next().unwrap_or_else(|| usage("No command given"));
+    let args: Vec<String> = args.collect();
+
+    let result = Modem::open(&port, baud_rate).and_then(|mut modem| {
+        modem.handshake()?;
+
+        match command.as_str() {
+            "ping" => ping(&mut modem, count(&args, 4)),
+            "announce" => match args.first().and_then(|addr| addr.parse().ok()) {
+                Some(addr) => {
+                    modem.send(&ModemUartMessages::AnnounceTurn(addr))?;
+                    println!("Announced the turn of {}", addr);
+                    Ok(())
+                }
+                None => usage("announce needs an address"),
+            },
+            "settings" => settings(&mut modem, &args),
+            "stream" => stream(&mut modem, args.iter().any(|arg| arg == "--sniff")),
+            "throughput" => {
+                let (count, window) = throughput_args(&args);
+                throughput(&mut modem, count, window)
+            }
+            "radioping" => {
+                let (addrs, count) = radioping_args(&args);
+                radioping(&mut modem, &addrs, count)
+            }
+            "send" => send(&mut modem, &args.join(" ")),
+            other => usage(&format!("Unknown command {}", other)),
+        }
+    });
+
+    if let Err(msg) = result {
+        eprintln!("{}", msg);
+        process::exit(1);
+    }
+}
+
+fn usage(msg: &str) -> ! {
+    eprintln!("{}\n\n{}", msg, USAGE);
+    process::exit(2);
+}
+
+fn count(args: &[String], default: u32) -> u32 {
+    match args.first() {
+        Some(count) => count.parse().unwrap_or_else(|_| usage("COUNT must be a number")),
+        None => default,
+    }
+}
+
+/// `[COUNT] [--window N]`
+fn throughput_args(args: &[String]) -> (u32, usize) {
+    let mut count = 1000;
+    let mut window = 4;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let parsed = match arg.as_str() {
+            "--window" => args.next().and_then(|n| n.parse().ok()).map(|n| window = n),
+            n => n.parse().ok().map(|n| count = n),
+        };
+        if parsed.is_none() || window == 0 {
+            usage("throughput takes a COUNT and a --window of at least 1");
+        }
+    }
+
+    (count, window)
+}
+
+/// `ADDR... [--count N]`, where each `ADDR` is an address or a range
+/// of them, like `1-16`
+fn radioping_args(args: &[String]) -> (Vec<u16>, u32) {
+    let mut addrs = Vec::new();
+    let mut count = 4;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let parsed = match arg.as_str() {
+            "--count" => args.next().and_then(|n| n.parse().ok()).map(|n| count = n),
+            range => match range.split_once('-') {
+                Some((first, last)) => first
+                    .parse()
+                    .and_then(|first: u16| last.parse().map(|last| addrs.extend(first..=last)))
+                    .ok(),
+                None => range.parse().ok().map(|addr| addrs.push(addr)),
+            },
+        };
+        if parsed.is_none() {
+            usage("radioping takes addresses like 3 or 1-16, and a --count");
+        }
+    }
+    if addrs.is_empty() {
+        usage("radioping needs at least one address");
+    }
+
+    (addrs, count)
+}
+
+fn ping(modem: &mut Modem, count: u32) -> Result<(), String> {
+    let mut rtts = Vec::new();
+
+    for seq in 0..count {
+        let value = 0x4242_0000 | (seq & 0xFFFF);
+        let start = Instant::now();
+        modem.send(&ModemUartMessages::Loopback(value))?;
+
+        let reply = modem.expect(REPLY_TIMEOUT, |msg| match msg {
+            ModemUartMessages::Loopback(val) if val == value => Some(()),
+            _ => None,
+        })?;
+
+        match reply {
+            Some(()) => {
+                let rtt = start.elapsed();
+                println!("Loopback {}: {:.1}ms", seq, millis(rtt));
+                rtts.push(rtt);
+            }
+            None => println!("Loopback {}: timed out", seq),
+        }
+
+        if seq + 1 < count {
+            sleep(Duration::from_millis(250));
+        }
+    }
+
+    println!("{} sent, {} answered", count, rtts.len());
+    if let (Some(min), Some(max)) = (rtts.iter().min(), rtts.iter().max()) {
+        let avg = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+        println!("Round trip min/avg/max: {:.1}/{:.1}/{:.1}ms", millis(*min), millis(avg), millis(*max));
+    }
+
+    Ok(())
+}
+
+/// Ping each client `count` times over the radio, and report the round
+/// trip times and how well the modem received the answers. Clients
+/// answer on their own schedule, so the round trip time mostly shows
+/// whether they answer at all.
+fn radioping(modem: &mut Modem, addrs: &[u16], count: u32) -> Result<(), String> {
+    if !modem.supports(capabilities::RADIO_PING, "pinging clients") {
+        return Ok(());
+    }
+
+    let mut reports = Vec::new();
+    for &addr in addrs {
+        let mut answers: Vec<(u32, RxQuality)> = Vec::new();
+
+        for _ in 0..count {
+            modem.send(&ModemUartMessages::Ping(addr))?;
+
+            let pong = modem.expect(REPLY_TIMEOUT, |msg| match msg {
+                ModemUartMessages::Pong(pong) if pong.source == addr => Some(pong),
+                _ => None,
+            })?;
+            if let Some(pong) = pong {
+                answers.push((pong.rtt_us, pong.quality));
+            }
+        }
+
+        eprintln!("{}: {} of {} answered", addr, answers.len(), count);
+        reports.push((addr, answers));
+    }
+
+    println!("{:>4}  {:<8}  {:<19}  {:>9}  {:>10}", "addr", "answered", "rtt min/avg/max", "rssi", "first path");
+    for (addr, answers) in reports {
+        if answers.is_empty() {
+            println!("{:>4}  {:>2} of {:<2}  no answer", addr, 0, count);
+            continue;
+        }
+
+        let rtts = answers.iter().map(|(rtt_us, _)| *rtt_us as f32 / 1000.0);
+        let min = rtts.clone().fold(f32::INFINITY, f32::min);
+        let max = rtts.clone().fold(0.0, f32::max);
+        let avg = rtts.sum::<f32>() / answers.len() as f32;
+        let rssi = average(answers.iter().map(|(_, quality)| quality.rssi_dbm()));
+        let first_path = average(answers.iter().map(|(_, quality)| quality.first_path_dbm()));
+
+        println!(
+            "{:>4}  {:>2} of {:<2}  {:>5.1}/{:>5.1}/{:>5.1}ms  {:>6.1}dBm  {:>7.1}dBm{}",
+            addr,
+            answers.len(),
+            count,
+            min,
+            avg,
+            max,
+            rssi,
+            first_path,
+            if rssi - first_path > BLOCKED_PATH_DB { "  blocked?" } else { "" },
+        );
+    }
+
+    Ok(())
+}
+
+fn average(values: impl ExactSizeIterator<Item = f32>) -> f32 {
+    let len = values.len() as f32;
+    values.sum::<f32>() / len
+}
+
+/// Settings which aren't given are reset to their defaults, as the
+/// modem can't report the ones it currently uses
+fn settings(modem: &mut Modem, args: &[String]) -> Result<(), String> {
+    if args.is_empty() {
+        usage("settings needs at least one KEY=VALUE");
+    }
+    if !modem.supports(capabilities::CONFIGURE, "runtime configuration") {
+        return Ok(());
+    }
+
+    let requested = match format!("configure {}", args.join(" ")).parse() {
+        Ok(ModemUartMessages::Configure(settings)) => settings,
+        Ok(_) => unreachable!("parsed a configure command"),
+        Err(e) => return Err(format!("Invalid settings: {}", e)),
+    };
+    modem.send(&ModemUartMessages::Configure(requested))?;
+
+    let applied = modem.expect(REPLY_TIMEOUT, |msg| match msg {
+        ModemUartMessages::Configured(settings) => Some(settings),
+        _ => None,
+    })?;
+
+    match applied {
+        Some(applied) => {
+            println!("{}", ModemUartMessages::Configured(applied));
+            if applied != requested {
+                eprintln!("Warning: the modem did not apply all requested settings");
+            }
+            Ok(())
+        }
+        None => Err("The modem did not confirm the settings".into()),
+    }
+}
+
+/// Print every message of the modem, until interrupted
+fn stream(modem: &mut Modem, sniff: bool) -> Result<(), String> {
+    if modem.supports(capabilities::CAPTURE, "capturing") {
+        modem.send(&ModemUartMessages::SetCapture(true))?;
+    }
+    if sniff && modem.supports(capabilities::SNIFFER, "sniffer mode") {
+        modem.send(&ModemUartMessages::SetSniffer(true))?;
+    }
+
+    let stdout = io::stdout();
+    loop {
+        if let Some(msg) = modem.recv(Instant::now() + REPLY_TIMEOUT)? {
+            let mut stdout = stdout.lock();
+            writeln!(stdout, "{}", msg)
+                .and_then(|_| stdout.flush())
+                .map_err(|e| format!("Failed to write output: {}", e))?;
+        }
+    }
+}
+
+/// Keep up to `window` `LoadLoopBack`s in flight until `count` were
+/// sent, and report the round trip times, rate and loss
+fn throughput(modem: &mut Modem, count: u32, window: usize) -> Result<(), String> {
+    if !modem.supports(capabilities::LOAD_LOOPBACK, "echoing LoadLoopBack") {
+        return Ok(());
+    }
+    let before = status(modem)?;
+
+    let frame_len = wire::to_uart(&ModemUartMessages::LoadLoopBack([0; 16]))
+        .map(|frame| frame.len())
+        .unwrap_or(0);
+    println!(
+        "Sending {} frames of {} bytes, up to {} ({} bytes) at once",
+        count,
+        frame_len,
+        window,
+        window * frame_len,
+    );
+
+    let mut in_flight = VecDeque::new();
+    let mut rtts = Vec::new();
+    let mut lost = 0;
+    let mut next = 0;
+
+    let start = Instant::now();
+    while next < count || !in_flight.is_empty() {
+        if next < count && in_flight.len() < window {
+            modem.send(&ModemUartMessages::LoadLoopBack([u64::from(next); 16]))?;
+            in_flight.push_back((u64::from(next), Instant::now()));
+            next += 1;
+            continue;
+        }
+
+        let echo = modem.expect(REPLY_TIMEOUT, |msg| match msg {
+            ModemUartMessages::LoadLoopBack(vals) => Some(vals[0]),
+            _ => None,
+        })?;
+
+        match echo {
+            // The serial link keeps frames in order, so frames sent
+            // before the one echoed which are still in flight are lost
+            Some(seq) => {
+                if let Some(idx) = in_flight.iter().position(|(sent, _)| *sent == seq) {
+                    lost += idx;
+                    let (_, sent_at) = in_flight.drain(..=idx).next_back().unwrap();
+                    rtts.push(sent_at.elapsed());
+                }
+            }
+            None => {
+                in_flight.pop_front();
+                lost += 1;
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+
+    println!(
+        "{} of {} frames echoed, {} lost ({:.1}%)",
+        rtts.len(),
+        count,
+        lost,
+        100.0 * lost as f64 / f64::from(count.max(1)),
+    );
+    println!(
+        "Sustained {:.0} frames/s, {:.0} bytes/s in each direction",
+        rtts.len() as f64 / elapsed.as_secs_f64(),
+        (rtts.len() * frame_len) as f64 / elapsed.as_secs_f64(),
+    );
+
+    rtts.sort();
+    if !rtts.is_empty() {
+        let percentile = |p: f64| millis(rtts[((rtts.len() - 1) as f64 * p).round() as usize]);
+        println!(
+            "Round trip min/p50/p90/p99/max: {:.1}/{:.1}/{:.1}/{:.1}/{:.1}ms",
+            percentile(0.0),
+            percentile(0.5),
+            percentile(0.9),
+            percentile(0.99),
+            percentile(1.0),
+        );
+    }
+
+    if let (Some(before), Some(after)) = (before, status(modem)?) {
+        println!(
+            "Modem dropped {} commands with a bad CRC, and lost {} bytes / {} messages",
+            after.crc_errors.wrapping_sub(before.crc_errors),
+            after.lost_bytes.wrapping_sub(before.lost_bytes),
+            after.lost_msgs.wrapping_sub(before.lost_msgs),
+        );
+    }
+
+    Ok(())
+}
+
+fn status(modem: &mut Modem) -> Result<Option<protocol::ModemStatus>, String> {
+    if !modem.supports(capabilities::STATUS, "status reports") {
+        return Ok(None);
+    }
+
+    modem.send(&ModemUartMessages::GetStatus)?;
+    modem.expect(REPLY_TIMEOUT, |msg| match msg {
+        ModemUartMessages::Status(status) => Some(status),
+        _ => None,
+    })
+}
+
+/// Send a message in text form, and print the answers
+fn send(modem: &mut Modem, text: &str) -> Result<(), String> {
+    let msg: ModemUartMessages = text.parse().map_err(|e| format!("Invalid message: {}", e))?;
+    modem.send(&msg)?;
+
+    let deadline = Instant::now() + REPLY_TIMEOUT;
+    while let Some(msg) = modem.recv(deadline)? {
+        println!("{}", msg);
+    }
+
+    Ok(())
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
@@ -0,0 +1,278 @@
+//! Decode a dump of COBS framed protocol messages
+//!
+//! Reads hex (e.g. copied from a logic analyser or serial terminal) or
+//! raw bytes from a file or stdin, splits them into frames at each zero
+//! byte, and prints every frame in the text form of `protocol::text`.
+//! Frames which fail to decode are reported with the offset of the
+//! byte that broke them.
+//!
+//! Usage: protocol-decoder [--raw] [--modem | --host | --radio] [FILE]
+//!
+//! * `--raw` - The input is binary, instead of hex
+//! * `--modem` - Frames sent by the modem to the host (default)
+//! * `--host` - Frames sent by the host to the modem
+//! * `--radio` - COBS framed `RadioMessages`
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Read};
+use std::process;
+
+use nrf52_bin_logger::LogOnLine;
+use postcard::{from_bytes, Error};
+use protocol::{envelope, CheckedFrame, ModemUartMessages, RadioMessages};
+use serde::Deserialize;
+
+#[derive(Clone, Copy)]
+enum Direction {
+    /// `LogOnLine<CheckedFrame>`, from the modem to the host
+    Modem,
+    /// `CheckedFrame`, from the host to the modem
+    Host,
+    Radio,
+}
+
+struct Args {
+    raw: bool,
+    direction: Direction,
+    path: Option<String>,
+}
+
+fn main() {
+    let args = match parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            eprintln!("Usage: protocol-decoder [--raw] [--modem | --host | --radio] [FILE]");
+            process::exit(2);
+        }
+    };
+
+    let mut input = Vec::new();
+    let read = match args.path {
+        Some(ref path) => File::open(path).and_then(|mut file| file.read_to_end(&mut input)),
+        None => io::stdin().read_to_end(&mut input),
+    };
+    if let Err(e) = read {
+        eprintln!("Failed to read input: {}", e);
+        process::exit(1);
+    }
+
+    let bytes = if args.raw {
+        input
+    } else {
+        match parse_hex(&String::from_utf8_lossy(&input)) {
+            Ok(bytes) => bytes,
+            Err(msg) => {
+                eprintln!("{}", msg);
+                process::exit(1);
+            }
+        }
+    };
+
+    let mut failed = false;
+    let mut start = 0;
+    for (index, frame) in bytes.split_inclusive(|b| *b == 0).enumerate() {
+        let encoded = frame.strip_suffix(&[0]).unwrap_or(frame);
+        let range = format!("{}..{}", start, start + frame.len());
+
+        if encoded.is_empty() {
+            // Zero bytes between frames are harmless
+        } else if encoded.len() == frame.len() {
+            println!("frame {} @ {}: no terminating zero, incomplete", index, range);
+            failed = true;
+        } else {
+            match decode(args.direction, encoded) {
+                Ok(text) => println!("frame {} @ {}: {}", index, range, text),
+                Err(e) => {
+                    println!("frame {} @ {}: {}", index, range, e.describe(start, encoded));
+                    failed = true;
+                }
+            }
+        }
+
+        start += frame.len();
+    }
+
+    if failed {
+        process::exit(1);
+    }
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args {
+        raw: false,
+        direction: Direction::Modem,
+        path: None,
+    };
+
+    for arg in args {
+        match arg.as_str() {
+            "--raw" => parsed.raw = true,
+            "--modem" => parsed.direction = Direction::Modem,
+            "--host" => parsed.direction = Direction::Host,
+            "--radio" => parsed.direction = Direction::Radio,
+            flag if flag.starts_with("--") => return Err(format!("Unknown option {}", flag)),
+            path if parsed.path.is_none() => parsed.path = Some(path.into()),
+            _ => return Err("Only one input file may be given".into()),
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Hex bytes, either separated by anything but letters and digits
+/// (e.g. `0a 00`, `0x0a, 0x00` or `0A:00`) or run together (`0a00`)
+fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+
+    for (line_no, line) in text.lines().enumerate() {
+        for token in line.split(|c: char| !c.is_ascii_alphanumeric()).filter(|t| !t.is_empty()) {
+            let digits = token.strip_prefix("0x").unwrap_or(token);
+            let invalid = || format!("Line {}: `{}` is not hex", line_no + 1, token);
+
+            if digits.len() % 2 != 0 || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(invalid());
+            }
+            for i in (0..digits.len()).step_by(2) {
+                bytes.push(u8::from_str_radix(&digits[i..i + 2], 16).map_err(|_| invalid())?);
+            }
+        }
+    }
+
+    Ok(bytes)
+}
+
+/// Why a frame didn't decode. Offsets are into the COBS decoded frame.
+enum FrameError {
+    /// A COBS code at this offset of the encoded frame points past its end
+    Cobs(usize),
+    /// Decoding failed at this offset
+    Decode(usize, Error),
+    /// The frame ended before the message was complete
+    Truncated,
+    /// The message ended at this offset, before the frame did
+    Trailing(usize),
+    Crc { expected: u32, actual: u32 },
+    /// A message type added in a newer protocol version
+    Unsupported(u32),
+}
+
+impl FrameError {
+    /// Describe the error, with offsets into the whole input
+    fn describe(&self, start: usize, encoded: &[u8]) -> String {
+        let offsets = cobs_decode(encoded).map(|(_, offsets)| offsets).unwrap_or_default();
+        let at = |offset: usize| match offsets.get(offset) {
+            Some(pos) => format!("byte {} (0x{:02x})", start + pos, encoded[*pos]),
+            None => format!("byte {}", start + encoded.len()),
+        };
+
+        match self {
+            FrameError::Cobs(pos) => format!(
+                "bad COBS code at byte {} (0x{:02x}), it points past the end of the frame",
+                start + pos,
+                encoded[*pos],
+            ),
+            FrameError::Decode(offset, e) => format!("decoding failed at {}: {:?}", at(*offset), e),
+            FrameError::Truncated => "frame ends before the message does".into(),
+            FrameError::Trailing(offset) => format!("unexpected data after the message, from {}", at(*offset)),
+            FrameError::Crc { expected, actual } => format!(
+                "CRC mismatch, frame says 0x{:08x} but the message has 0x{:08x}",
+                expected, actual,
+            ),
+            FrameError::Unsupported(index) => format!("unsupported message type {}, sent by a newer peer?", index),
+        }
+    }
+}
+
+fn decode(direction: Direction, encoded: &[u8]) -> Result<String, FrameError> {
+    let (data, _) = cobs_decode(encoded).map_err(FrameError::Cobs)?;
+
+    match direction {
+        Direction::Radio => {
+            exactly::<RadioMessages>(&data, RadioMessages::VARIANTS).map(|msg| msg.to_string())
+        }
+        Direction::Host => {
+            let frame = exactly::<CheckedFrame>(&data, 0)?;
+            message(&frame, data.len()).map(|msg| msg.to_string())
+        }
+        Direction::Modem => match exactly::<LogOnLine<CheckedFrame>>(&data, 0)? {
+            LogOnLine::Log(log) => Ok(format!("log: {}", log)),
+            LogOnLine::Warn(log) => Ok(format!("warn: {}", log)),
+            LogOnLine::Error(log) => Ok(format!("error: {}", log)),
+            LogOnLine::BinaryRaw(bin) => Ok(format!("binary: {:02x?}", bin)),
+            LogOnLine::ProtocolMessage(frame) => message(&frame, data.len()).map(|msg| msg.to_string()),
+        },
+    }
+}
+
+/// The message in a `CheckedFrame` which ends `end` bytes into the
+/// decoded frame
+fn message(frame: &CheckedFrame, end: usize) -> Result<ModemUartMessages, FrameError> {
+    frame.check().map_err(|_| FrameError::Crc {
+        expected: frame.crc,
+        actual: protocol::crc32(&frame.bytes),
+    })?;
+
+    // The message bytes are the last field of a `CheckedFrame`
+    let start = end - frame.bytes.len();
+    exactly::<ModemUartMessages>(&frame.bytes, ModemUartMessages::VARIANTS).map_err(|e| match e {
+        FrameError::Decode(offset, e) => FrameError::Decode(start + offset, e),
+        FrameError::Trailing(offset) => FrameError::Trailing(start + offset),
+        other => other,
+    })
+}
+
+/// Decode a value which takes up all of `bytes`. Postcard doesn't
+/// report where decoding failed, so decode ever longer prefixes: the
+/// first one which fails with anything else than running out of bytes
+/// ends with the culprit. `variants` is the number of variants of the
+/// enum being decoded, to tell apart unknown message types, or zero.
+fn exactly<'a, T: Deserialize<'a>>(bytes: &'a [u8], variants: u32) -> Result<T, FrameError> {
+    for len in 0..=bytes.len() {
+        match from_bytes::<T>(&bytes[..len]) {
+            Ok(_) if len < bytes.len() => return Err(FrameError::Trailing(len)),
+            Ok(value) => return Ok(value),
+            Err(Error::DeserializeUnexpectedEnd) => {}
+            Err(e) => {
+                if variants != 0 {
+                    if let Some(index) = envelope::unsupported(bytes, variants) {
+                        return Err(FrameError::Unsupported(index));
+                    }
+                }
+                return Err(FrameError::Decode(len - 1, e));
+            }
+        }
+    }
+
+    Err(FrameError::Truncated)
+}
+
+/// Decode a COBS frame without its terminating zero. Also returns, for
+/// each decoded byte, its offset in the encoded frame. A zero restored
+/// by the decoding is at the offset of the code byte which replaced it.
+fn cobs_decode(encoded: &[u8]) -> Result<(Vec<u8>, Vec<usize>), usize> {
+    let mut data = Vec::new();
+    let mut offsets = Vec::new();
+
+    let mut pos = 0;
+    while pos < encoded.len() {
+        let code = usize::from(encoded[pos]);
+        if code == 0 || pos + code > encoded.len() {
+            return Err(pos);
+        }
+
+        for (offset, byte) in encoded.iter().enumerate().take(pos + code).skip(pos + 1) {
+            data.push(*byte);
+            offsets.push(offset);
+        }
+
+        pos += code;
+        if code < 0xff && pos < encoded.len() {
+            data.push(0);
+            offsets.push(pos);
+        }
+    }
+
+    Ok((data, offsets))
+}
@@ -2,6 +2,13 @@
 
 set -o errexit
 
+(
+    echo "=> Building protocol ..."
+    cd protocol
+    cargo build
+    cargo build --features modem,std
+)
+
 for path in advanced beginner beginner-lite; do
     (
         echo "=> Building $path/templates ..."
@@ -0,0 +1,138 @@
+//! Proving which board sent a message
+//!
+//! Boards choose their short address themselves, so any board could
+//! pretend to be another one. To prevent this, the instructor can give
+//! every student a `Key` for their short address. Boards with a key
+//! append a `TAG_LEN` byte tag to the payload of every frame they send,
+//! see `sign()`. The tag covers the sender's short address and the
+//! serialized message. The modem checks it with `verify()`, and drops
+//! all frames from that address without a valid tag.
+//!
+//! Messages from addresses without a key are accepted as before. Tags
+//! don't protect against replaying a recorded frame.
+
+use core::hash::Hasher;
+
+use heapless::{consts::*, Vec};
+use siphasher::sip::SipHasher24;
+
+/// Length of the tag appended to every frame
+pub const TAG_LEN: usize = 4;
+
+/// A SipHash key, shared by a board and the server
+pub type Key = [u8; 16];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AuthError {
+    /// The buffer has no room for the tag
+    BufferFull,
+    /// The payload is too short to carry a tag
+    Missing,
+    /// The tag doesn't match the message
+    Mismatch,
+    /// The `Keyring` has no room for another key
+    KeyringFull,
+}
+
+/// Tag of `message`, sent by `source`
+pub fn tag(key: &Key, source: u16, message: &[u8]) -> [u8; TAG_LEN] {
+    let mut hasher = SipHasher24::new_with_key(key);
+    hasher.write(&source.to_le_bytes());
+    hasher.write(message);
+
+    let mut tag = [0; TAG_LEN];
+    tag.copy_from_slice(&hasher.finish().to_le_bytes()[..TAG_LEN]);
+    tag
+}
+
+/// Append the tag to the first `len` bytes of `buf`, which hold the
+/// serialized message. Returns the payload to transmit.
+pub fn sign<'a>(key: &Key, source: u16, buf: &'a mut [u8], len: usize) -> Result<&'a [u8], AuthError> {
+    let total = len + TAG_LEN;
+    if total > buf.len() {
+        return Err(AuthError::BufferFull);
+    }
+
+    let tag = tag(key, source, &buf[..len]);
+    buf[len..total].copy_from_slice(&tag);
+
+    Ok(&buf[..total])
+}
+
+/// Check the tag at the end of a received payload. Returns the
+/// serialized message without the tag.
+pub fn verify<'a>(key: &Key, source: u16, payload: &'a [u8]) -> Result<&'a [u8], AuthError> {
+    if payload.len() < TAG_LEN {
+        return Err(AuthError::Missing);
+    }
+
+    let (message, received) = payload.split_at(payload.len() - TAG_LEN);
+    let expected = tag(key, source, message);
+
+    // Look at every byte, so the time taken doesn't tell how many matched
+    let diff = expected
+        .iter()
+        .zip(received)
+        .fold(0, |acc, (a, b)| acc | (a ^ b));
+
+    if diff == 0 {
+        Ok(message)
+    } else {
+        Err(AuthError::Mismatch)
+    }
+}
+
+/// Parse a key written as 32 hex digits
+pub fn parse_key(hex: &str) -> Option<Key> {
+    let hex = hex.as_bytes();
+    if hex.len() != 2 * 16 {
+        return None;
+    }
+
+    let mut key = [0; 16];
+    for (byte, digits) in key.iter_mut().zip(hex.chunks(2)) {
+        let digits = core::str::from_utf8(digits).ok()?;
+        *byte = u8::from_str_radix(digits, 16).ok()?;
+    }
+
+    Some(key)
+}
+
+/// The keys of up to 32 boards, by short address
+#[derive(Default)]
+pub struct Keyring {
+    keys: Vec<(u16, Key), U32>,
+}
+
+impl Keyring {
+    pub fn new() -> Self {
+        Keyring {
+            keys: Vec::new(),
+        }
+    }
+
+    /// Set or remove the key of `client`
+    pub fn set(&mut self, client: u16, key: Option<Key>) -> Result<(), AuthError> {
+        let pos = self.keys.iter().position(|(addr, _)| *addr == client);
+
+        match (pos, key) {
+            (Some(pos), Some(key)) => self.keys[pos].1 = key,
+            (Some(pos), None) => {
+                self.keys.swap_remove(pos);
+            }
+            (None, Some(key)) => {
+                self.keys.push((client, key)).map_err(|_| AuthError::KeyringFull)?;
+            }
+            (None, None) => {}
+        }
+
+        Ok(())
+    }
+
+    pub fn get(&self, client: u16) -> Option<&Key> {
+        self.keys
+            .iter()
+            .find(|(addr, _)| *addr == client)
+            .map(|(_, key)| key)
+    }
+}
@@ -0,0 +1,91 @@
+//! Rows and columns of the board
+//!
+//! Coordinates are 1-based and at most 255, and take a single byte on
+//! the wire, on the boards as well as on the host. They are checked
+//! when created and when decoded, so a message with a coordinate of
+//! zero is rejected by the receiver.
+
+use core::convert::TryFrom;
+use core::fmt;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// A row or column, from 1 to 255
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Coord(u8);
+
+/// The value is zero, or larger than 255
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct OutOfRange;
+
+impl Coord {
+    pub const MIN: Coord = Coord(1);
+    pub const MAX: Coord = Coord(u8::MAX);
+
+    pub const fn new(value: u8) -> Option<Coord> {
+        if value == 0 {
+            None
+        } else {
+            Some(Coord(value))
+        }
+    }
+
+    pub const fn get(self) -> u8 {
+        self.0
+    }
+
+    /// The coordinate `by` rows or columns further, if still in range
+    pub fn offset(self, by: usize) -> Option<Coord> {
+        let value = usize::from(self.0).checked_add(by)?;
+        Coord::try_from(value).ok()
+    }
+}
+
+impl TryFrom<usize> for Coord {
+    type Error = OutOfRange;
+
+    fn try_from(value: usize) -> Result<Self, OutOfRange> {
+        let value = u8::try_from(value).map_err(|_| OutOfRange)?;
+        Coord::new(value).ok_or(OutOfRange)
+    }
+}
+
+impl From<Coord> for u8 {
+    fn from(coord: Coord) -> u8 {
+        coord.0
+    }
+}
+
+impl From<Coord> for usize {
+    fn from(coord: Coord) -> usize {
+        usize::from(coord.0)
+    }
+}
+
+impl fmt::Display for Coord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl fmt::Display for OutOfRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("coordinates must be between 1 and 255")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for OutOfRange {}
+
+impl Serialize for Coord {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for Coord {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = u8::deserialize(deserializer)?;
+        Coord::new(value).ok_or_else(|| de::Error::custom(OutOfRange))
+    }
+}
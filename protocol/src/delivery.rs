@@ -0,0 +1,160 @@
+//! Acknowledged delivery of drawing messages
+//!
+//! A client sends its drawing as a `RadioMessages::Sequenced`. Once the
+//! server has processed it, the modem replies with a `RadioMessages::Ack`
+//! or `RadioMessages::Nack` carrying the same sequence number. The
+//! `Outbox` keeps track of this, and retries messages which were not
+//! answered in time.
+//!
+//! Clients which don't use sequence numbers are sent a
+//! `RadioMessages::Rejected` instead, when their drawing is rejected.
+
+use heapless::{consts::*, Vec};
+use serde::{Serialize, Deserialize};
+
+use crate::{Cell, RadioMessages, Shape};
+
+/// A drawing message which should be acknowledged
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Sequenced {
+    pub seq: u8,
+    pub shape: Shape,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Nack {
+    pub seq: u8,
+    pub reason: RejectReason,
+}
+
+/// Feedback on a rejected drawing message without sequence number
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Rejected {
+    pub reason: RejectReason,
+    /// The rejected cell, or the first cell of a rejected shape
+    pub cell: Cell,
+}
+
+/// Why a drawing message was not drawn
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub enum RejectReason {
+    /// No part of the shape is within the sender's area
+    OutOfRange,
+    /// Another player is drawing right now
+    NotYourTurn,
+    /// The sender exceeded the number of messages per second
+    RateLimited,
+}
+
+/// What became of a message sent through the `Outbox`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Outcome {
+    Accepted,
+    Rejected(RejectReason),
+}
+
+/// Something the `Outbox` needs to be done
+#[derive(Clone, Debug, Eq, PartialEq)]
+// There is no allocator on the boards, so variants can't be boxed
+#[allow(clippy::large_enum_variant)]
+pub enum Action {
+    /// Transmit this message again
+    Retry(RadioMessages),
+    /// No answer arrived for the message with this sequence number,
+    /// even after all retries
+    GiveUp(u8),
+}
+
+struct Pending {
+    message: Sequenced,
+    attempts: u8,
+    next_retry_ms: u32,
+}
+
+/// Keeps up to eight drawing messages until they are acknowledged
+pub struct Outbox {
+    pending: Vec<Pending, U8>,
+    next_seq: u8,
+    retry_ms: u32,
+    max_attempts: u8,
+}
+
+impl Outbox {
+    /// Unanswered messages are sent again after `retry_ms`, doubling
+    /// the delay on every attempt, up to `max_attempts` transmissions
+    pub fn new(retry_ms: u32, max_attempts: u8) -> Self {
+        Outbox {
+            pending: Vec::new(),
+            next_seq: 0,
+            retry_ms,
+            max_attempts,
+        }
+    }
+
+    /// Queue a shape for acknowledged delivery, and return the message
+    /// to transmit. If the outbox is full, the shape is handed back.
+    #[allow(clippy::result_large_err)]
+    pub fn send(&mut self, shape: Shape, now_ms: u32) -> Result<RadioMessages, Shape> {
+        if self.pending.len() == self.pending.capacity() {
+            return Err(shape);
+        }
+
+        let message = Sequenced {
+            seq: self.next_seq,
+            shape,
+        };
+        self.next_seq = self.next_seq.wrapping_add(1);
+
+        let radio = RadioMessages::Sequenced(message.clone());
+        let pending = Pending {
+            message,
+            attempts: 1,
+            next_retry_ms: now_ms.wrapping_add(self.retry_ms),
+        };
+
+        // Can't fail, we checked for space above
+        let _ = self.pending.push(pending);
+
+        Ok(radio)
+    }
+
+    /// Process a message received from the modem. Returns the sequence
+    /// number and outcome if it answers a pending message.
+    pub fn receive(&mut self, msg: &RadioMessages) -> Option<(u8, Outcome)> {
+        let (seq, outcome) = match msg {
+            RadioMessages::Ack(seq) => (*seq, Outcome::Accepted),
+            RadioMessages::Nack(nack) => (nack.seq, Outcome::Rejected(nack.reason)),
+            _ => return None,
+        };
+
+        let pos = self.pending.iter().position(|p| p.message.seq == seq)?;
+        self.pending.swap_remove(pos);
+
+        Some((seq, outcome))
+    }
+
+    /// Call this regularly. Returns what needs to be done, if anything.
+    /// Call it again until it returns `None`.
+    pub fn poll(&mut self, now_ms: u32) -> Option<Action> {
+        // Wrapping comparison, so the millisecond counter may overflow
+        let due = |p: &Pending| now_ms.wrapping_sub(p.next_retry_ms) < u32::MAX / 2;
+        let pos = self.pending.iter().position(due)?;
+
+        if self.pending[pos].attempts >= self.max_attempts {
+            let pending = self.pending.swap_remove(pos);
+            return Some(Action::GiveUp(pending.message.seq));
+        }
+
+        let pending = &mut self.pending[pos];
+        let backoff = self.retry_ms << pending.attempts.min(6);
+        pending.attempts += 1;
+        pending.next_retry_ms = now_ms.wrapping_add(backoff);
+
+        Some(Action::Retry(RadioMessages::Sequenced(pending.message.clone())))
+    }
+
+    /// Number of messages waiting for an answer
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
@@ -0,0 +1,450 @@
+//! Drawing primitives covering more than one cell
+//!
+//! All coordinates are 1-based, like those of a `Cell`. Cells which
+//! would end up beyond row or column 255 are left out. Every primitive
+//! except for large blits fits into a single radio frame, which limits
+//! the number of colours or cells they can carry. Larger blits must be
+//! sent in fragments, see the `fragment` module.
+
+use core::convert::TryFrom;
+
+use heapless::{consts::*, Vec};
+use serde::{Serialize, Deserialize};
+
+use crate::{Cell, Coord};
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Color {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
+
+/// A filled rectangle, with its top left corner at `row`/`column`
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Rect {
+    pub row: Coord,
+    pub column: Coord,
+    pub width: u8,
+    pub height: u8,
+    pub color: Color,
+}
+
+/// A straight line between two cells, including both ends
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Line {
+    pub from_row: Coord,
+    pub from_column: Coord,
+    pub to_row: Coord,
+    pub to_column: Coord,
+    pub color: Color,
+}
+
+/// A run of colours, starting at `row`/`column` and going right
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Row {
+    pub row: Coord,
+    pub column: Coord,
+    pub colors: Vec<Color, U32>,
+}
+
+/// A short list of individual cells
+pub type Cells = Vec<Cell, U16>;
+
+/// How the pixels of a `Blit` are stored
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub enum BlitEncoding {
+    /// Three bits per pixel, least significant bits first
+    Packed,
+    /// One byte per run of up to 32 pixels with the same colour. The
+    /// lower three bits are the colour index, the upper five bits are
+    /// the length of the run minus one.
+    RunLength,
+}
+
+/// Reasons a `Blit` can't be created
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BlitError {
+    /// The width is zero, more than 255 rows would be needed, or the
+    /// last row is incomplete
+    Size,
+    /// A pixel refers to a palette index above 7
+    Index,
+    /// The palette or the encoded image don't fit into a message
+    TooLarge,
+}
+
+/// A small image using up to eight colours, with its top left corner
+/// at `row`/`column`. Pixels are stored row by row, as indices into
+/// the palette. Indices without a palette entry are transparent.
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Blit {
+    pub row: Coord,
+    pub column: Coord,
+    pub width: u8,
+    pub height: u8,
+    pub palette: Vec<Color, U8>,
+    pub encoding: BlitEncoding,
+    pub data: Vec<u8, U384>,
+}
+
+impl Blit {
+    /// Largest image that is guaranteed to fit with `Blit::packed()`,
+    /// e.g. a 32x32 board
+    pub const MAX_PACKED_PIXELS: usize = 384 * 8 / 3;
+
+    /// Largest image that is guaranteed to fit into a single radio
+    /// frame with `Blit::packed()`
+    pub const FRAME_PACKED_PIXELS: usize = 80 * 8 / 3;
+
+    /// Create a blit with three bits per pixel. `pixels` contains one
+    /// palette index per pixel, row by row. Fails if the image does not
+    /// fit into a single message.
+    pub fn packed(
+        row: Coord,
+        column: Coord,
+        width: u8,
+        palette: &[Color],
+        pixels: &[u8],
+    ) -> Result<Self, BlitError> {
+        let mut blit = Blit::empty(row, column, width, palette, pixels, BlitEncoding::Packed)?;
+
+        for _ in 0..(pixels.len() * 3).div_ceil(8) {
+            blit.data.push(0).map_err(|_| BlitError::TooLarge)?;
+        }
+
+        for (i, px) in pixels.iter().enumerate() {
+            let bit = i * 3;
+            let bits = u16::from(*px) << (bit % 8);
+
+            blit.data[bit / 8] |= bits as u8;
+            if let Some(next) = blit.data.get_mut(bit / 8 + 1) {
+                *next |= (bits >> 8) as u8;
+            }
+        }
+
+        Ok(blit)
+    }
+
+    /// Create a run-length encoded blit, which allows larger images
+    /// if they consist of runs of the same colour. `pixels` contains one
+    /// palette index per pixel, row by row. Fails if the image does not
+    /// fit into a single message.
+    pub fn run_length(
+        row: Coord,
+        column: Coord,
+        width: u8,
+        palette: &[Color],
+        pixels: &[u8],
+    ) -> Result<Self, BlitError> {
+        let mut blit = Blit::empty(row, column, width, palette, pixels, BlitEncoding::RunLength)?;
+
+        let mut rest = pixels;
+        while let Some(&px) = rest.first() {
+            let run = rest.iter().take(32).take_while(|&&p| p == px).count();
+            blit.data
+                .push(((run as u8 - 1) << 3) | px)
+                .map_err(|_| BlitError::TooLarge)?;
+            rest = &rest[run..];
+        }
+
+        Ok(blit)
+    }
+
+    fn empty(
+        row: Coord,
+        column: Coord,
+        width: u8,
+        palette: &[Color],
+        pixels: &[u8],
+        encoding: BlitEncoding,
+    ) -> Result<Self, BlitError> {
+        if width == 0 {
+            return Err(BlitError::Size);
+        }
+        let height = pixels.len() / usize::from(width);
+        if height * usize::from(width) != pixels.len() || height > usize::from(u8::MAX) {
+            return Err(BlitError::Size);
+        }
+        if pixels.iter().any(|&px| px > 7) {
+            return Err(BlitError::Index);
+        }
+
+        let mut blit = Blit {
+            row,
+            column,
+            width,
+            height: height as u8,
+            palette: Vec::new(),
+            encoding,
+            data: Vec::new(),
+        };
+        blit.palette
+            .extend_from_slice(palette)
+            .map_err(|_| BlitError::TooLarge)?;
+
+        Ok(blit)
+    }
+}
+
+/// Anything that can be drawn on the board
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+// There is no allocator on the boards, so variants can't be boxed
+#[allow(clippy::large_enum_variant)]
+pub enum Shape {
+    Cell(Cell),
+    FillRect(Rect),
+    Line(Line),
+    Row(Row),
+    Cells(Cells),
+    Blit(Blit),
+}
+
+impl Shape {
+    /// Restrict the shape to rows `1..=rows` and columns `1..=columns`,
+    /// so expanding it does not produce cells that would be thrown away
+    /// anyway. Lines are not clipped, blits only at the bottom.
+    pub fn clip(&mut self, rows: usize, columns: usize) {
+        match self {
+            Shape::FillRect(rect) => {
+                let width = (columns + 1).saturating_sub(usize::from(rect.column));
+                let height = (rows + 1).saturating_sub(usize::from(rect.row));
+                rect.width = usize::from(rect.width).min(width) as u8;
+                rect.height = usize::from(rect.height).min(height) as u8;
+            }
+            Shape::Row(row) => {
+                let len = if usize::from(row.row) > rows {
+                    0
+                } else {
+                    (columns + 1).saturating_sub(usize::from(row.column))
+                };
+                row.colors = row.colors.iter().take(len).cloned().collect();
+            }
+            Shape::Cells(cells) => {
+                *cells = cells
+                    .iter()
+                    .filter(|c| usize::from(c.row) <= rows && usize::from(c.column) <= columns)
+                    .cloned()
+                    .collect();
+            }
+            Shape::Blit(blit) => {
+                let visible = (rows + 1).saturating_sub(usize::from(blit.row));
+                if visible < usize::from(blit.height) {
+                    blit.height = visible as u8;
+                }
+            }
+            Shape::Cell(_) | Shape::Line(_) => {}
+        }
+    }
+
+    /// Iterate over all cells covered by this shape
+    pub fn cells(&self) -> ShapeCells<'_> {
+        let state = match self {
+            Shape::Cell(cell) => State::Single(Some(*cell)),
+            Shape::FillRect(rect) => State::Rect { rect: *rect, idx: 0 },
+            Shape::Line(line) => State::Line(Bresenham::new(line)),
+            Shape::Row(row) => State::Row { row, idx: 0 },
+            Shape::Cells(cells) => State::Cells(cells.iter()),
+            Shape::Blit(blit) => State::Blit(BlitCells::new(blit)),
+        };
+
+        ShapeCells { state }
+    }
+}
+
+/// Iterator over the cells of a `Shape`, see `Shape::cells()`
+pub struct ShapeCells<'a> {
+    state: State<'a>,
+}
+
+enum State<'a> {
+    Single(Option<Cell>),
+    Rect { rect: Rect, idx: usize },
+    Line(Bresenham),
+    Row { row: &'a Row, idx: usize },
+    Cells(core::slice::Iter<'a, Cell>),
+    Blit(BlitCells<'a>),
+}
+
+impl<'a> Iterator for ShapeCells<'a> {
+    type Item = Cell;
+
+    fn next(&mut self) -> Option<Cell> {
+        match self.state {
+            State::Single(ref mut cell) => cell.take(),
+            State::Rect { ref rect, ref mut idx } => {
+                let width = usize::from(rect.width);
+                let total = width * usize::from(rect.height);
+
+                while *idx < total {
+                    let row = rect.row.offset(*idx / width);
+                    let column = rect.column.offset(*idx % width);
+                    *idx += 1;
+
+                    if let (Some(row), Some(column)) = (row, column) {
+                        return Some(cell_at(row, column, rect.color));
+                    }
+                }
+
+                None
+            }
+            State::Line(ref mut line) => line.next(),
+            State::Row { row, ref mut idx } => {
+                let color = *row.colors.get(*idx)?;
+                let cell = cell_at(row.row, row.column.offset(*idx)?, color);
+                *idx += 1;
+                Some(cell)
+            }
+            State::Cells(ref mut iter) => iter.next().cloned(),
+            State::Blit(ref mut blit) => blit.next(),
+        }
+    }
+}
+
+fn cell_at(row: Coord, column: Coord, color: Color) -> Cell {
+    Cell {
+        row,
+        column,
+        red: color.red,
+        green: color.green,
+        blue: color.blue,
+    }
+}
+
+/// Decodes the pixels of a `Blit`, skipping transparent ones
+struct BlitCells<'a> {
+    blit: &'a Blit,
+    pixel: usize,
+    // Position in `data`, and the remainder of the current run,
+    // for run-length encoded blits
+    byte: usize,
+    run: usize,
+    index: u8,
+}
+
+impl<'a> BlitCells<'a> {
+    fn new(blit: &'a Blit) -> Self {
+        BlitCells {
+            blit,
+            pixel: 0,
+            byte: 0,
+            run: 0,
+            index: 0,
+        }
+    }
+}
+
+impl<'a> Iterator for BlitCells<'a> {
+    type Item = Cell;
+
+    fn next(&mut self) -> Option<Cell> {
+        let width = usize::from(self.blit.width);
+        let total = width * usize::from(self.blit.height);
+
+        while self.pixel < total {
+            let index = match self.blit.encoding {
+                BlitEncoding::Packed => {
+                    let bit = self.pixel * 3;
+                    let low = u16::from(*self.blit.data.get(bit / 8)?);
+                    let high = u16::from(self.blit.data.get(bit / 8 + 1).cloned().unwrap_or(0));
+                    (((high << 8 | low) >> (bit % 8)) & 0b111) as u8
+                }
+                BlitEncoding::RunLength => {
+                    if self.run == 0 {
+                        let byte = *self.blit.data.get(self.byte)?;
+                        self.byte += 1;
+                        self.index = byte & 0b111;
+                        self.run = usize::from(byte >> 3) + 1;
+                    }
+                    self.run -= 1;
+                    self.index
+                }
+            };
+
+            let pixel = self.pixel;
+            self.pixel += 1;
+
+            let row = self.blit.row.offset(pixel / width);
+            let column = self.blit.column.offset(pixel % width);
+
+            let color = self.blit.palette.get(usize::from(index));
+
+            if let (Some(color), Some(row), Some(column)) = (color, row, column) {
+                return Some(cell_at(row, column, *color));
+            }
+        }
+
+        None
+    }
+}
+
+/// Bresenham's line algorithm, in signed coordinates so lines
+/// may go in any direction
+struct Bresenham {
+    row: isize,
+    column: isize,
+    to_row: isize,
+    to_column: isize,
+    d_row: isize,
+    d_column: isize,
+    step_row: isize,
+    step_column: isize,
+    err: isize,
+    color: Color,
+    done: bool,
+}
+
+impl Bresenham {
+    fn new(line: &Line) -> Self {
+        let coord = |c: Coord| isize::from(c.get());
+        let (row, column) = (coord(line.from_row), coord(line.from_column));
+        let (to_row, to_column) = (coord(line.to_row), coord(line.to_column));
+        let d_column = (to_column - column).abs();
+        let d_row = -(to_row - row).abs();
+
+        Bresenham {
+            row,
+            column,
+            to_row,
+            to_column,
+            d_row,
+            d_column,
+            step_row: if row < to_row { 1 } else { -1 },
+            step_column: if column < to_column { 1 } else { -1 },
+            err: d_column + d_row,
+            color: line.color,
+            done: false,
+        }
+    }
+}
+
+impl Iterator for Bresenham {
+    type Item = Cell;
+
+    fn next(&mut self) -> Option<Cell> {
+        if self.done {
+            return None;
+        }
+
+        // All cells are between the two ends, so in range
+        let row = Coord::try_from(self.row as usize).ok()?;
+        let column = Coord::try_from(self.column as usize).ok()?;
+        let cell = cell_at(row, column, self.color);
+
+        if self.row == self.to_row && self.column == self.to_column {
+            self.done = true;
+        } else {
+            let e2 = 2 * self.err;
+            if e2 >= self.d_row {
+                self.err += self.d_row;
+                self.column += self.step_column;
+            }
+            if e2 <= self.d_column {
+                self.err += self.d_column;
+                self.row += self.step_row;
+            }
+        }
+
+        Some(cell)
+    }
+}
@@ -0,0 +1,33 @@
+//! Skipping messages from newer peers
+//!
+//! Every message travels in an envelope which tells its length: a radio
+//! frame on the air, a `CheckedFrame` over the UART. A message which
+//! fails to decode therefore never affects the following ones. Postcard
+//! writes the variant index of an enum first, so the receiver can tell
+//! messages of a type added by a newer peer apart from broken ones, and
+//! skip them as unsupported.
+//!
+//! New message types must be appended at the end of their enum, and the
+//! `VARIANTS` constant of the enum increased.
+
+/// Variant index of a serialized enum, e.g. a `RadioMessages`
+pub fn variant_index(bytes: &[u8]) -> Option<u32> {
+    let mut index = 0u32;
+
+    // Variable length encoding, 7 bits per byte, lowest bits first
+    for (i, byte) in bytes.iter().take(5).enumerate() {
+        index |= u32::from(byte & 0x7F) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some(index);
+        }
+    }
+
+    None
+}
+
+/// The variant index of a message which failed to decode, if it is
+/// a type not known to this build. `known` is the number of variants
+/// of the enum, e.g. `RadioMessages::VARIANTS`.
+pub fn unsupported(bytes: &[u8], known: u32) -> Option<u32> {
+    variant_index(bytes).filter(|index| *index >= known)
+}
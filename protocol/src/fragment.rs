@@ -0,0 +1,214 @@
+//! Sending messages that don't fit into a single radio frame
+//!
+//! The sender serializes the message as usual. If it is longer than
+//! `FRAME_PAYLOAD`, it is split with `fragments()`, and every fragment
+//! is sent as a `RadioMessages::Fragment`. The receiver feeds all
+//! fragments into a `Reassembler`, which returns the serialized message
+//! once all of its fragments have arrived.
+
+use heapless::{consts::*, Vec};
+use serde::{Serialize, Deserialize};
+
+/// Largest payload that fits into a single radio frame, when sent with
+/// short addresses and a compressed PAN ID
+pub const FRAME_PAYLOAD: usize = 116;
+
+/// Bytes of the message carried by each fragment. Only the last
+/// fragment of a message may be shorter.
+pub const FRAGMENT_DATA: usize = 96;
+
+/// Maximum number of fragments of a single message
+pub const MAX_FRAGMENTS: usize = 16;
+
+/// Largest message that can be fragmented
+pub const MAX_MESSAGE_LEN: usize = FRAGMENT_DATA * MAX_FRAGMENTS;
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct FragmentHeader {
+    /// Chosen by the sender, must differ between consecutive messages
+    pub message_id: u8,
+    pub index: u8,
+    pub count: u8,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Fragment {
+    pub header: FragmentHeader,
+    pub data: Vec<u8, U96>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FragmentError {
+    /// The message is empty or longer than `MAX_MESSAGE_LEN`
+    MessageSize,
+    /// The fragment contradicts itself or earlier fragments
+    Invalid,
+    /// Too many messages are being reassembled at the same time
+    Busy,
+}
+
+/// Split a serialized message into fragments
+pub fn fragments(message_id: u8, message: &[u8]) -> Result<Fragments<'_>, FragmentError> {
+    if message.is_empty() || message.len() > MAX_MESSAGE_LEN {
+        return Err(FragmentError::MessageSize);
+    }
+
+    Ok(Fragments {
+        message_id,
+        count: message.len().div_ceil(FRAGMENT_DATA) as u8,
+        chunks: message.chunks(FRAGMENT_DATA).enumerate(),
+    })
+}
+
+/// Iterator over the fragments of a message, see `fragments()`
+pub struct Fragments<'a> {
+    message_id: u8,
+    count: u8,
+    chunks: core::iter::Enumerate<core::slice::Chunks<'a, u8>>,
+}
+
+impl<'a> Iterator for Fragments<'a> {
+    type Item = Fragment;
+
+    fn next(&mut self) -> Option<Fragment> {
+        let (index, chunk) = self.chunks.next()?;
+        let mut data = Vec::new();
+
+        // Can't fail, chunks are at most FRAGMENT_DATA long
+        data.extend_from_slice(chunk).ok()?;
+
+        Some(Fragment {
+            header: FragmentHeader {
+                message_id: self.message_id,
+                index: index as u8,
+                count: self.count,
+            },
+            data,
+        })
+    }
+}
+
+/// A message of which some fragments have been received
+struct Partial {
+    source: u16,
+    header: FragmentHeader,
+    /// Bitmask of the fragments received so far
+    received: u16,
+    len: usize,
+    started_ms: u32,
+    complete: bool,
+    data: [u8; MAX_MESSAGE_LEN],
+}
+
+/// Reassembles fragmented messages, from up to four senders at once
+pub struct Reassembler {
+    partials: Vec<Partial, U4>,
+    timeout_ms: u32,
+    timeouts: u32,
+}
+
+impl Reassembler {
+    /// Messages are dropped if they are not complete within
+    /// `timeout_ms` after their first fragment arrived
+    pub fn new(timeout_ms: u32) -> Self {
+        Reassembler {
+            partials: Vec::new(),
+            timeout_ms,
+            timeouts: 0,
+        }
+    }
+
+    /// Add a fragment received from `source`. Once the last missing
+    /// fragment of a message arrives, the serialized message is returned.
+    pub fn push(
+        &mut self,
+        source: u16,
+        fragment: &Fragment,
+        now_ms: u32,
+    ) -> Result<Option<&[u8]>, FragmentError> {
+        let hdr = fragment.header;
+        let count = usize::from(hdr.count);
+        let index = usize::from(hdr.index);
+        let last = index + 1 == count;
+
+        if count == 0 || count > MAX_FRAGMENTS || index >= count {
+            return Err(FragmentError::Invalid);
+        }
+        if fragment.data.is_empty() || (!last && fragment.data.len() != FRAGMENT_DATA) {
+            return Err(FragmentError::Invalid);
+        }
+
+        // Completed messages have been handed out on the last call
+        self.remove(|p| p.complete);
+        self.expire(now_ms);
+
+        // A new message id from the same source replaces the old message
+        self.remove(|p| p.source == source && p.header.message_id != hdr.message_id);
+
+        let pos = match self.partials.iter().position(|p| p.source == source) {
+            Some(pos) => pos,
+            None => {
+                self.partials
+                    .push(Partial {
+                        source,
+                        header: hdr,
+                        received: 0,
+                        len: 0,
+                        started_ms: now_ms,
+                        complete: false,
+                        data: [0; MAX_MESSAGE_LEN],
+                    })
+                    .map_err(|_| FragmentError::Busy)?;
+                self.partials.len() - 1
+            }
+        };
+
+        let partial = &mut self.partials[pos];
+        if partial.header.count != hdr.count {
+            return Err(FragmentError::Invalid);
+        }
+
+        let start = index * FRAGMENT_DATA;
+        partial.data[start..start + fragment.data.len()].copy_from_slice(&fragment.data);
+        partial.received |= 1 << index;
+        if last {
+            partial.len = start + fragment.data.len();
+        }
+
+        if partial.received.count_ones() as usize == count {
+            partial.complete = true;
+            Ok(Some(&partial.data[..partial.len]))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Drop messages which timed out. This also happens on every
+    /// `push()`, but calling it regularly frees memory sooner.
+    pub fn expire(&mut self, now_ms: u32) {
+        let timeout_ms = self.timeout_ms;
+        let expired = self.remove(|p| !p.complete && now_ms.wrapping_sub(p.started_ms) > timeout_ms);
+        self.timeouts = self.timeouts.wrapping_add(expired as u32);
+    }
+
+    /// Number of messages dropped because they timed out
+    pub fn timeouts(&self) -> u32 {
+        self.timeouts
+    }
+
+    fn remove<F: Fn(&Partial) -> bool>(&mut self, pred: F) -> usize {
+        let mut removed = 0;
+        let mut i = 0;
+
+        while i < self.partials.len() {
+            if pred(&self.partials[i]) {
+                self.partials.swap_remove(i);
+                removed += 1;
+            } else {
+                i += 1;
+            }
+        }
+
+        removed
+    }
+}
@@ -0,0 +1,71 @@
+//! Getting a short address from the server
+//!
+//! A board without an address sets its own address to `JOIN_ADDRESS`,
+//! and sends a `RadioMessages::Join` with its device ID to the modem.
+//! The server picks an address, and the modem sends it back to
+//! `JOIN_ADDRESS` as a `RadioMessages::Assigned`. As several boards may
+//! be joining at once, each must check the device ID of the answer.
+//! A board always gets the same address back, so joining again after
+//! a reset is fine.
+
+use serde::{Serialize, Deserialize};
+
+use crate::{RadioMessages, Region};
+
+/// Address used by boards while they wait for an `Assigned`
+pub const JOIN_ADDRESS: u16 = 0xFFFE;
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Join {
+    /// A unique ID of the board, e.g. the `DEVICEID` of the nRF52
+    pub device_id: u64,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Assigned {
+    /// The board this answer is for
+    pub device_id: u64,
+    pub short_addr: u16,
+    /// The area the board may draw in, in board coordinates. In
+    /// partitioned mode, boards draw relative to its top left corner.
+    pub partition: Region,
+}
+
+/// Joins from a board, until it got its address
+pub struct Joiner {
+    device_id: u64,
+    assigned: Option<Assigned>,
+}
+
+impl Joiner {
+    pub fn new(device_id: u64) -> Self {
+        Joiner {
+            device_id,
+            assigned: None,
+        }
+    }
+
+    /// The message to send to the modem, from `JOIN_ADDRESS`. Repeat
+    /// it every now and then, until `assigned()` returns something.
+    pub fn request(&self) -> RadioMessages {
+        RadioMessages::Join(Join {
+            device_id: self.device_id,
+        })
+    }
+
+    /// Process a received message. Returns the assignment, if the
+    /// message is the answer for this board.
+    pub fn receive(&mut self, msg: &RadioMessages) -> Option<Assigned> {
+        match msg {
+            RadioMessages::Assigned(assigned) if assigned.device_id == self.device_id => {
+                self.assigned = Some(*assigned);
+                self.assigned
+            }
+            _ => None,
+        }
+    }
+
+    pub fn assigned(&self) -> Option<Assigned> {
+        self.assigned
+    }
+}
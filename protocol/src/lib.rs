@@ -0,0 +1,146 @@
+//! Message definitions for the radio drawing game
+//!
+//! This crate is shared by every course. By default it only contains
+//! the messages sent over the radio by the participants' boards. The
+//! following cargo features add more:
+//!
+//! * `modem` - Messages exchanged between the `draw-modem` and the
+//!   `draw-server` over the serial port, as used by the beginner course
+//! * `std` - Conveniences for host applications, like a text form of
+//!   the messages (see `text`) and their encoding on the wire (see `wire`)
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+use serde::{Serialize, Deserialize};
+
+pub mod auth;
+pub mod coord;
+pub mod delivery;
+pub mod draw;
+pub mod envelope;
+pub mod fragment;
+pub mod join;
+pub mod presence;
+pub mod query;
+pub use coord::Coord;
+pub use delivery::{Nack, RejectReason, Rejected, Sequenced};
+pub use fragment::Fragment;
+pub use join::{Assigned, Join};
+pub use presence::Heartbeat;
+pub use query::{CellState, Position, Region};
+pub use draw::{Blit, BlitEncoding, BlitError, Cells, Color, Line, Rect, Row, Shape};
+
+#[cfg(feature = "std")]
+pub mod text;
+#[cfg(feature = "std")]
+pub mod wire;
+
+#[cfg(feature = "modem")]
+mod modem;
+#[cfg(feature = "modem")]
+pub use modem::*;
+
+/// Version of the wire format defined in this crate. Bump this on
+/// every change to the messages that older peers can't decode. Peers
+/// skip message types added at the end of an enum, see `envelope`.
+pub const PROTOCOL_VERSION: u16 = 15;
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[allow(clippy::large_enum_variant)]
+pub enum RadioMessages {
+    // Messages from clients to modem
+    SetCell(Cell),
+
+    // Messages from modem to clients
+    StartTurn(u16),
+
+    // Bulk drawing, from clients to modem
+    FillRect(Rect),
+    Line(Line),
+    SetRow(Row),
+    SetCells(Cells),
+
+    // A whole image, from clients to modem
+    Blit(Blit),
+
+    // Part of a message too large for a single frame, see `fragment`
+    Fragment(Fragment),
+
+    // Acknowledged drawing, see `delivery`
+    Sequenced(Sequenced),
+    Ack(u8),
+    Nack(Nack),
+
+    // Feedback from modem to clients
+    Rejected(Rejected),
+
+    // Reading back the board, see `query`
+    GetCell(Position),
+    GetRegion(Region),
+    CellState(CellState),
+
+    // Getting an address, see `join`
+    Join(Join),
+    Assigned(Assigned),
+
+    // Presence, from clients to modem, see `presence`
+    Heartbeat(Heartbeat),
+
+    // Radio link test. The modem sends a `Ping` when asked to by the
+    // host, and clients answer with a `Pong` carrying the same value
+    Ping(u32),
+    Pong(u32),
+}
+
+impl RadioMessages {
+    /// Number of variants, see `envelope`
+    pub const VARIANTS: u32 = 20;
+
+    /// The shape to draw, if this is a drawing message
+    pub fn into_shape(self) -> Option<Shape> {
+        match self {
+            RadioMessages::SetCell(cell) => Some(Shape::Cell(cell)),
+            RadioMessages::FillRect(rect) => Some(Shape::FillRect(rect)),
+            RadioMessages::Line(line) => Some(Shape::Line(line)),
+            RadioMessages::SetRow(row) => Some(Shape::Row(row)),
+            RadioMessages::SetCells(cells) => Some(Shape::Cells(cells)),
+            RadioMessages::Blit(blit) => Some(Shape::Blit(blit)),
+            RadioMessages::Sequenced(msg) => Some(msg.shape),
+            RadioMessages::StartTurn(_)
+            | RadioMessages::Fragment(_)
+            | RadioMessages::Ack(_)
+            | RadioMessages::Nack(_)
+            | RadioMessages::Rejected(_)
+            | RadioMessages::GetCell(_)
+            | RadioMessages::GetRegion(_)
+            | RadioMessages::CellState(_)
+            | RadioMessages::Join(_)
+            | RadioMessages::Assigned(_)
+            | RadioMessages::Heartbeat(_)
+            | RadioMessages::Ping(_)
+            | RadioMessages::Pong(_) => None,
+        }
+    }
+}
+
+impl From<Shape> for RadioMessages {
+    fn from(shape: Shape) -> Self {
+        match shape {
+            Shape::Cell(cell) => RadioMessages::SetCell(cell),
+            Shape::FillRect(rect) => RadioMessages::FillRect(rect),
+            Shape::Line(line) => RadioMessages::Line(line),
+            Shape::Row(row) => RadioMessages::SetRow(row),
+            Shape::Cells(cells) => RadioMessages::SetCells(cells),
+            Shape::Blit(blit) => RadioMessages::Blit(blit),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Cell {
+    pub row: Coord,
+    pub column: Coord,
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+}
@@ -0,0 +1,625 @@
+use core::fmt;
+
+use heapless::{consts::*, Vec};
+use serde::{Serialize, Deserialize};
+
+use crate::{
+    auth::Key, Assigned, Blit, Cell, CellState, Heartbeat, Join, Nack, RadioMessages, Region, RejectReason,
+    Rejected, Shape,
+};
+
+/// Optional features a peer supports, as advertised in `Hello`
+pub mod capabilities {
+    pub const CAPTURE: u32 = 1 << 0;
+    pub const SNIFFER: u32 = 1 << 1;
+    pub const CONFIGURE: u32 = 1 << 2;
+    pub const STATUS: u32 = 1 << 3;
+    pub const AUTH: u32 = 1 << 4;
+    /// Echoes `LoadLoopBack`, for benchmarking the serial link
+    pub const LOAD_LOOPBACK: u32 = 1 << 5;
+    /// Pings clients over the radio, see `ModemUartMessages::Ping`
+    pub const RADIO_PING: u32 = 1 << 6;
+}
+
+/// Largest serialized `ModemUartMessages` carried by a `CheckedFrame`
+pub const MAX_UART_MESSAGE: usize = 480;
+
+/// A serialized `ModemUartMessages` and its CRC-32. Messages in both
+/// directions are wrapped in one of these, so corrupted bytes which
+/// still happen to decode are noticed.
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct CheckedFrame {
+    pub crc: u32,
+    pub bytes: Vec<u8, U480>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CheckError {
+    /// The message is longer than `MAX_UART_MESSAGE`
+    TooLarge,
+    /// The bytes don't match the CRC
+    Mismatch,
+}
+
+impl CheckedFrame {
+    /// Wrap a serialized message
+    pub fn new(bytes: &[u8]) -> Result<Self, CheckError> {
+        let mut frame = CheckedFrame {
+            crc: crc32(bytes),
+            bytes: Vec::new(),
+        };
+        frame.bytes.extend_from_slice(bytes).map_err(|_| CheckError::TooLarge)?;
+
+        Ok(frame)
+    }
+
+    /// The serialized message, if it matches the CRC
+    pub fn check(&self) -> Result<&[u8], CheckError> {
+        if crc32(&self.bytes) == self.crc {
+            Ok(&self.bytes)
+        } else {
+            Err(CheckError::Mismatch)
+        }
+    }
+}
+
+/// CRC-32 as used by Ethernet and zlib
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+#[allow(clippy::large_enum_variant)]
+pub enum ModemUartMessages {
+    // Messages to the host system
+    SetCell(CellCommand),
+
+    // Commands to the embedded device
+    Loopback(u32),
+    AnnounceTurn(u16),
+
+    // Benchmarking. The modem echoes this back unchanged, like a
+    // `Loopback`, see `capabilities::LOAD_LOOPBACK`
+    LoadLoopBack([u64; 16]),
+
+    // Radio capture. When enabled by the host, the modem sends
+    // a `RadioFrame` for every frame it receives
+    SetCapture(bool),
+    RadioFrame(RadioFrame),
+
+    // Sniffer mode. When enabled by the host, the modem disables
+    // hardware address filtering, and sends a `Sniffed` for every
+    // frame it receives, including those not meant for the modem
+    SetSniffer(bool),
+    Sniffed(SniffedFrame),
+
+    // Runtime configuration. The modem answers `Configure` with
+    // `Configured`, containing the settings it actually applied
+    Configure(ModemSettings),
+    Configured(ModemSettings),
+
+    // Health reporting. The modem sends a `Status` periodically,
+    // and in response to `GetStatus`
+    GetStatus,
+    Status(ModemStatus),
+
+    // Version handshake. The host sends a `Hello` when it connects,
+    // which the modem answers with its own. The modem also sends one
+    // when it boots.
+    //
+    // NOTE: This variant must never change its position, so peers
+    // with different versions can still recognize each other
+    Hello(Hello),
+
+    // Messages to the host system
+    Draw(DrawCommand),
+    Blit(BlitCommand),
+
+    // Messages to the modem
+    Acknowledge(Acknowledge),
+    Reject(RejectCommand),
+
+    // Reading back the board. The modem forwards client requests as
+    // `Query`, the host answers with one or more `Reply`s
+    Query(QueryCommand),
+    Reply(ReplyCommand),
+
+    // Address assignment. The modem forwards a client's `Join`, the
+    // host answers with `Assign`, which the modem sends to `JOIN_ADDRESS`
+    Join(Join),
+    Assign(Assigned),
+
+    // A client's `Heartbeat`, forwarded by the modem
+    Heartbeat(HeartbeatCommand),
+
+    // Sets the key a client signs its frames with, see `auth`
+    SetKey(ClientKey),
+
+    // Radio link test. The host asks the modem to ping a short
+    // address, and the modem reports the client's answer
+    Ping(u16),
+    Pong(PongReport),
+}
+
+impl ModemUartMessages {
+    /// Number of variants, see `envelope`
+    pub const VARIANTS: u32 = 25;
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct CellCommand {
+    pub source: u16,
+    pub dest: u16,
+    pub rx: Reception,
+    pub cell: Cell,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct HeartbeatCommand {
+    pub source: u16,
+    pub rx: Reception,
+    pub heartbeat: Heartbeat,
+}
+
+/// A bulk drawing message received from a client
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct DrawCommand {
+    pub source: u16,
+    pub dest: u16,
+    pub rx: Reception,
+    pub shape: Shape,
+    /// Set if the client expects an `Acknowledge`
+    pub seq: Option<u8>,
+}
+
+impl From<CellCommand> for DrawCommand {
+    fn from(cmd: CellCommand) -> Self {
+        DrawCommand {
+            source: cmd.source,
+            dest: cmd.dest,
+            rx: cmd.rx,
+            shape: Shape::Cell(cmd.cell),
+            seq: None,
+        }
+    }
+}
+
+/// An image received from a client
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct BlitCommand {
+    pub source: u16,
+    pub dest: u16,
+    pub rx: Reception,
+    pub blit: Blit,
+}
+
+impl From<BlitCommand> for DrawCommand {
+    fn from(cmd: BlitCommand) -> Self {
+        DrawCommand {
+            source: cmd.source,
+            dest: cmd.dest,
+            rx: cmd.rx,
+            shape: Shape::Blit(cmd.blit),
+            seq: None,
+        }
+    }
+}
+
+/// Tells the modem to answer a `Sequenced` message of a client with
+/// an `Ack`, or with a `Nack` if it was rejected
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Acknowledge {
+    pub client: u16,
+    pub seq: u8,
+    pub rejection: Option<RejectReason>,
+}
+
+impl Acknowledge {
+    /// The message to send to the client
+    pub fn to_radio(&self) -> RadioMessages {
+        match self.rejection {
+            None => RadioMessages::Ack(self.seq),
+            Some(reason) => RadioMessages::Nack(Nack {
+                seq: self.seq,
+                reason,
+            }),
+        }
+    }
+}
+
+/// Tells the modem to send a `RadioMessages::Rejected` to a client
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct RejectCommand {
+    pub client: u16,
+    pub rejected: Rejected,
+}
+
+/// A request for the colours of a region, received from a client
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct QueryCommand {
+    pub source: u16,
+    pub dest: u16,
+    pub rx: Reception,
+    pub region: Region,
+}
+
+/// Tells the modem to send a `RadioMessages::CellState` to a client
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct ReplyCommand {
+    pub client: u16,
+    pub state: CellState,
+}
+
+/// Tells the modem to check the frames of `client` against `key`.
+/// `None` accepts unsigned frames from the client again.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct ClientKey {
+    pub client: u16,
+    pub key: Option<Key>,
+}
+
+/// A client's answer to a `RadioMessages::Ping` sent by the modem
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct PongReport {
+    pub source: u16,
+    /// Time between sending the `Ping` and receiving the `Pong`
+    pub rtt_us: u32,
+    /// How well the modem received the `Pong`
+    pub quality: RxQuality,
+}
+
+/// When and how well the modem received a client's message. Attached
+/// to every message the modem forwards from a client, except `Join`s,
+/// which all come from `JOIN_ADDRESS`.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq, Default)]
+pub struct Reception {
+    /// Uptime of the modem when the frame arrived. Wraps around after
+    /// about 71 minutes.
+    pub timestamp_us: u32,
+    /// All zero if the modem failed to read it from the DW1000
+    pub quality: RxQuality,
+}
+
+/// If the first path is this much weaker than the whole signal, the
+/// direct path between a client and the modem is likely blocked
+pub const BLOCKED_PATH_DB: f32 = 6.0;
+
+/// Receive quality of a frame, as reported by the DW1000. These are
+/// raw register values, see section 4.7 of the DW1000 user manual.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq, Default)]
+pub struct RxQuality {
+    /// Power of the channel impulse response (CIR_PWR)
+    pub cir_power: u16,
+    /// Amplitudes of the first path (FP_AMPL1 to FP_AMPL3)
+    pub fp_ampl: [u16; 3],
+    /// Number of preamble symbols accumulated (RXPACC)
+    pub preamble_count: u16,
+    /// Standard deviation of the noise (STD_NOISE)
+    pub std_noise: u16,
+    /// The frame was sent with a pulse repetition frequency of
+    /// 64 MHz, instead of 16 MHz
+    pub prf_64mhz: bool,
+}
+
+#[cfg(feature = "std")]
+impl RxQuality {
+    /// Estimated receive power, in dBm
+    pub fn rssi_dbm(&self) -> f32 {
+        self.level(f32::from(self.cir_power) * 131_072.0)
+    }
+
+    /// Estimated power of the first path, in dBm. If this is more than
+    /// `BLOCKED_PATH_DB` below `rssi_dbm()`, the direct path was likely
+    /// blocked.
+    pub fn first_path_dbm(&self) -> f32 {
+        let power: f32 = self.fp_ampl.iter().map(|ampl| f32::from(*ampl).powi(2)).sum();
+        self.level(power)
+    }
+
+    fn level(&self, power: f32) -> f32 {
+        if self.preamble_count == 0 {
+            return f32::NEG_INFINITY;
+        }
+
+        let correction = if self.prf_64mhz { 121.74 } else { 113.77 };
+        10.0 * (power / f32::from(self.preamble_count).powi(2)).log10() - correction
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Hello {
+    /// The `PROTOCOL_VERSION` the peer was built with
+    pub protocol_version: u16,
+    /// Version of the firmware or host application
+    pub firmware_version: Version,
+    /// Bitmask of `capabilities`
+    pub capabilities: u32,
+}
+
+impl Hello {
+    pub fn supports(&self, capability: u32) -> bool {
+        (self.capabilities & capability) == capability
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Version {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Radio settings of the modem
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct ModemSettings {
+    /// PAN ID the modem receives on
+    pub pan_id: u16,
+    /// Short address the modem receives on
+    pub short_addr: u16,
+    /// PAN ID used to broadcast `StartTurn` messages
+    pub broadcast_pan: u16,
+    /// UWB channel
+    pub channel: u8,
+    pub data_rate: DataRate,
+    /// How long the modem listens for frames before checking
+    /// for commands from the host, in microseconds
+    pub rx_window_us: u32,
+}
+
+impl Default for ModemSettings {
+    fn default() -> Self {
+        ModemSettings {
+            pan_id: 0x0386,
+            short_addr: 0x0808,
+            broadcast_pan: 0x0386,
+            channel: 5,
+            data_rate: DataRate::Mbps6_8,
+            rx_window_us: 100_000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub enum DataRate {
+    Kbps110,
+    Kbps850,
+    Mbps6_8,
+}
+
+/// Health and statistics of the modem. All counters start at
+/// zero when the modem boots.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq, Default)]
+pub struct ModemStatus {
+    pub uptime_ms: u32,
+    pub frames_received: u32,
+    pub rejected: RejectionCounters,
+    /// Bytes dropped by the UART logger
+    pub lost_bytes: u32,
+    /// Messages dropped by the UART logger
+    pub lost_msgs: u32,
+    /// Failures to start or complete a receive
+    pub rx_errors: u32,
+    /// Fragmented messages dropped, as not all fragments arrived in time
+    pub reassembly_timeouts: u32,
+    /// Commands from the host dropped, as they didn't match their CRC
+    pub crc_errors: u32,
+}
+
+/// Number of received frames dropped, per `FrameRejection`
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq, Default)]
+pub struct RejectionCounters {
+    pub bad_source: u32,
+    pub bad_destination: u32,
+    pub broadcast_pan: u32,
+    pub broadcast_source: u32,
+    pub pan_mismatch: u32,
+    pub not_for_modem: u32,
+    pub decode_failed: u32,
+    pub unexpected: u32,
+    pub bad_fragment: u32,
+    pub bad_tag: u32,
+    pub unsupported: u32,
+}
+
+impl RejectionCounters {
+    pub fn count(&mut self, rejection: FrameRejection) {
+        let ctr = match rejection {
+            FrameRejection::BadSource => &mut self.bad_source,
+            FrameRejection::BadDestination => &mut self.bad_destination,
+            FrameRejection::BroadcastPan => &mut self.broadcast_pan,
+            FrameRejection::BroadcastSource => &mut self.broadcast_source,
+            FrameRejection::PanMismatch => &mut self.pan_mismatch,
+            FrameRejection::NotForModem => &mut self.not_for_modem,
+            FrameRejection::DecodeFailed => &mut self.decode_failed,
+            FrameRejection::Unexpected => &mut self.unexpected,
+            FrameRejection::BadFragment => &mut self.bad_fragment,
+            FrameRejection::BadTag => &mut self.bad_tag,
+            FrameRejection::Unsupported => &mut self.unsupported,
+        };
+        *ctr = ctr.wrapping_add(1);
+    }
+
+    pub fn total(&self) -> u32 {
+        self.bad_source
+            .wrapping_add(self.bad_destination)
+            .wrapping_add(self.broadcast_pan)
+            .wrapping_add(self.broadcast_source)
+            .wrapping_add(self.pan_mismatch)
+            .wrapping_add(self.not_for_modem)
+            .wrapping_add(self.decode_failed)
+            .wrapping_add(self.unexpected)
+            .wrapping_add(self.bad_fragment)
+            .wrapping_add(self.bad_tag)
+            .wrapping_add(self.unsupported)
+    }
+}
+
+/// A frame received by the modem, as seen on the radio
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct RadioFrame {
+    pub header: FrameHeader,
+    pub payload: Vec<u8, U127>,
+}
+
+#[cfg(feature = "std")]
+impl RadioFrame {
+    /// Rebuild the on-air MAC frame, without the FCS
+    pub fn to_mac_frame(&self) -> std::vec::Vec<u8> {
+        let hdr = &self.header;
+        let mut out = std::vec::Vec::with_capacity(23 + self.payload.len());
+
+        let frame_type: u16 = match hdr.frame_type {
+            FrameType::Beacon => 0b000,
+            FrameType::Data => 0b001,
+            FrameType::Acknowledgement => 0b010,
+            FrameType::MacCommand => 0b011,
+        };
+
+        let fc = frame_type
+            | ((hdr.frame_pending as u16) << 4)
+            | ((hdr.ack_request as u16) << 5)
+            | ((hdr.pan_id_compress as u16) << 6)
+            | (hdr.destination.mode() << 10)
+            | (hdr.source.mode() << 14);
+
+        out.extend_from_slice(&fc.to_le_bytes());
+        out.push(hdr.seq);
+
+        hdr.destination.push_to(&mut out, true);
+        hdr.source.push_to(&mut out, !hdr.pan_id_compress);
+
+        out.extend_from_slice(&self.payload);
+        out
+    }
+}
+
+/// A frame received in sniffer mode, and how the modem handled it
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct SniffedFrame {
+    pub frame: RadioFrame,
+
+    /// `None` if the modem accepted the frame
+    pub rejection: Option<FrameRejection>,
+}
+
+/// Reasons for the modem to drop a received frame
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub enum FrameRejection {
+    /// The source is not a short address
+    BadSource,
+    /// The destination is not a short address
+    BadDestination,
+    /// The source PAN is the broadcast PAN
+    BroadcastPan,
+    /// The source address is the broadcast address
+    BroadcastSource,
+    /// Source and destination PAN differ
+    PanMismatch,
+    /// The frame is addressed to another device
+    NotForModem,
+    /// The payload is not a valid `RadioMessages`
+    DecodeFailed,
+    /// The payload is a message clients may not send
+    Unexpected,
+    /// The payload is a fragment which can't be reassembled
+    BadFragment,
+    /// The sender has a key, but the frame has no valid tag
+    BadTag,
+    /// The payload is a message type added in a newer protocol version
+    Unsupported,
+}
+
+impl fmt::Display for FrameRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let desc = match self {
+            FrameRejection::BadSource => "source is not a short address",
+            FrameRejection::BadDestination => "destination is not a short address",
+            FrameRejection::BroadcastPan => "sent from the broadcast PAN",
+            FrameRejection::BroadcastSource => "sent from the broadcast address",
+            FrameRejection::PanMismatch => "source and destination PAN differ",
+            FrameRejection::NotForModem => "not addressed to the modem",
+            FrameRejection::DecodeFailed => "payload could not be decoded",
+            FrameRejection::Unexpected => "message may not be sent by clients",
+            FrameRejection::BadFragment => "fragment can't be reassembled",
+            FrameRejection::BadTag => "tag missing or invalid",
+            FrameRejection::Unsupported => "message type not supported",
+        };
+        f.write_str(desc)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for FrameRejection {}
+
+/// The IEEE 802.15.4 MAC header fields of a received frame
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct FrameHeader {
+    pub frame_type: FrameType,
+    pub frame_pending: bool,
+    pub ack_request: bool,
+    pub pan_id_compress: bool,
+    pub seq: u8,
+    pub destination: FrameAddress,
+    pub source: FrameAddress,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub enum FrameType {
+    Beacon,
+    Data,
+    Acknowledgement,
+    MacCommand,
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub enum FrameAddress {
+    None,
+    Short { pan: u16, addr: u16 },
+    Extended { pan: u16, addr: u64 },
+}
+
+#[cfg(feature = "std")]
+impl FrameAddress {
+    /// Addressing mode, as encoded in the frame control field
+    fn mode(&self) -> u16 {
+        match self {
+            FrameAddress::None => 0b00,
+            FrameAddress::Short { .. } => 0b10,
+            FrameAddress::Extended { .. } => 0b11,
+        }
+    }
+
+    fn push_to(&self, out: &mut std::vec::Vec<u8>, with_pan: bool) {
+        match *self {
+            FrameAddress::None => {}
+            FrameAddress::Short { pan, addr } => {
+                if with_pan {
+                    out.extend_from_slice(&pan.to_le_bytes());
+                }
+                out.extend_from_slice(&addr.to_le_bytes());
+            }
+            FrameAddress::Extended { pan, addr } => {
+                if with_pan {
+                    out.extend_from_slice(&pan.to_le_bytes());
+                }
+                out.extend_from_slice(&addr.to_le_bytes());
+            }
+        }
+    }
+}
@@ -0,0 +1,20 @@
+//! Letting the server know a board is still around
+//!
+//! Clients send a `RadioMessages::Heartbeat` every
+//! `HEARTBEAT_INTERVAL_MS`. The server keeps a roster of the clients
+//! it heard from, and marks those it didn't hear from in a while as
+//! offline. Any other message from a client counts as well.
+
+use heapless::{consts::*, String};
+use serde::{Serialize, Deserialize};
+
+/// How often clients should send a heartbeat
+pub const HEARTBEAT_INTERVAL_MS: u32 = 2_000;
+
+#[derive(Clone, Serialize, Deserialize, Debug, Default, Eq, PartialEq)]
+pub struct Heartbeat {
+    /// Supply voltage of the board, in millivolts
+    pub battery_mv: Option<u16>,
+    /// Name or version of the firmware, e.g. `"segment-4 v0.1"`
+    pub firmware: Option<String<U16>>,
+}
@@ -0,0 +1,86 @@
+//! Reading back the colours shown on the board
+//!
+//! A client sends a `RadioMessages::GetCell` or `RadioMessages::GetRegion`
+//! to the modem. The server answers with one or more
+//! `RadioMessages::CellState`s, each covering up to `STATE_CELLS` cells
+//! of the requested region. Coordinates are the same as for drawing.
+
+use heapless::{consts::*, Vec};
+use serde::{Serialize, Deserialize};
+
+use crate::{Color, Coord};
+
+/// Number of cells carried by a single `CellState`
+pub const STATE_CELLS: usize = 24;
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Position {
+    pub row: Coord,
+    pub column: Coord,
+}
+
+/// A rectangle of cells, with its top left corner at `row`/`column`
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct Region {
+    pub row: Coord,
+    pub column: Coord,
+    pub width: u8,
+    pub height: u8,
+}
+
+impl Region {
+    /// Number of cells in the region
+    pub fn len(&self) -> usize {
+        usize::from(self.width) * usize::from(self.height)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Position of the cell at `index`, counting row by row
+    pub fn position(&self, index: usize) -> Option<Position> {
+        if index >= self.len() {
+            return None;
+        }
+
+        Some(Position {
+            row: self.row.offset(index / usize::from(self.width))?,
+            column: self.column.offset(index % usize::from(self.width))?,
+        })
+    }
+}
+
+impl From<Position> for Region {
+    fn from(pos: Position) -> Self {
+        Region {
+            row: pos.row,
+            column: pos.column,
+            width: 1,
+            height: 1,
+        }
+    }
+}
+
+/// Colours of part of a requested region, starting with the cell at
+/// `offset`, row by row. Cells outside of the requesting client's area,
+/// and cells not drawn since the server started, have no colour.
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct CellState {
+    pub region: Region,
+    pub offset: u16,
+    pub colors: Vec<Option<Color>, U24>,
+}
+
+impl CellState {
+    /// Iterate over the positions and colours of the cells in this reply
+    pub fn cells(&self) -> impl Iterator<Item = (Position, Option<Color>)> + '_ {
+        self.colors
+            .iter()
+            .enumerate()
+            .filter_map(move |(i, color)| {
+                let pos = self.region.position(usize::from(self.offset) + i)?;
+                Some((pos, *color))
+            })
+    }
+}
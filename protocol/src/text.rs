@@ -0,0 +1,1246 @@
+//! Human readable messages, for debugging and scripting
+//!
+//! Every message is written as a keyword followed by its fields,
+//! separated by spaces, e.g. `setcell 3 4 #ff0000` or `announce 7`.
+//! Fields appear in the order of the message struct, with nested
+//! structs flattened. Colours are written as `#rrggbb`, binary data in
+//! hex, and missing optional fields as `-`. Numbers may be given in
+//! hex with a `0x` prefix. A message printed with `Display` parses back
+//! into the same message with `FromStr`.
+//!
+//! `RadioMessages` and `ModemUartMessages` use different keywords where
+//! their messages differ, e.g. a `setcell` sent to the modem also has
+//! the source and destination address.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::{FromStr, SplitWhitespace};
+
+use heapless::ArrayLength;
+
+use crate::{
+    BlitEncoding, Blit, Cell, CellState, Color, Coord, Fragment, Heartbeat, Line, RadioMessages,
+    Rect, Region, RejectReason, Row, Shape,
+};
+use crate::fragment::FragmentHeader;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseError(String);
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn error<T>(msg: String) -> Result<T, ParseError> {
+    Err(ParseError(msg))
+}
+
+impl fmt::Display for Color {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.red, self.green, self.blue)
+    }
+}
+
+impl FromStr for Color {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        let hex = match s.strip_prefix('#') {
+            Some(hex) if hex.len() == 6 && hex.is_ascii() => hex,
+            _ => return error(format!("invalid colour `{}`, expected e.g. #ff0000", s)),
+        };
+        let channel = |i: usize| {
+            u8::from_str_radix(&hex[i..i + 2], 16)
+                .map_err(|_| ParseError(format!("invalid colour `{}`, expected e.g. #ff0000", s)))
+        };
+
+        Ok(Color {
+            red: channel(0)?,
+            green: channel(2)?,
+            blue: channel(4)?,
+        })
+    }
+}
+
+impl fmt::Display for Shape {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Shape::Cell(cell) => write!(f, "setcell {}", CellText(cell)),
+            Shape::FillRect(rect) => write!(f, "fillrect {}", RectText(rect)),
+            Shape::Line(line) => write!(f, "line {}", LineText(line)),
+            Shape::Row(row) => write!(f, "setrow {}", RowText(row)),
+            Shape::Cells(cells) => {
+                f.write_str("setcells")?;
+                for cell in cells.iter() {
+                    write!(f, " {}", CellText(cell))?;
+                }
+                Ok(())
+            }
+            Shape::Blit(blit) => write!(f, "blit {}", BlitText(blit)),
+        }
+    }
+}
+
+impl FromStr for Shape {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        let mut tokens = Tokens::new(s);
+        let keyword = tokens.next("a shape")?;
+        let shape = match tokens.shape(keyword)? {
+            Some(shape) => shape,
+            None => return error(format!("unknown shape `{}`", keyword)),
+        };
+        tokens.end()?;
+
+        Ok(shape)
+    }
+}
+
+impl fmt::Display for RadioMessages {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use RadioMessages::*;
+
+        match self {
+            SetCell(cell) => write!(f, "setcell {}", CellText(cell)),
+            StartTurn(addr) => write!(f, "startturn {}", addr),
+            FillRect(rect) => write!(f, "fillrect {}", RectText(rect)),
+            Line(line) => write!(f, "line {}", LineText(line)),
+            SetRow(row) => write!(f, "setrow {}", RowText(row)),
+            SetCells(cells) => Shape::Cells(cells.clone()).fmt(f),
+            Blit(blit) => write!(f, "blit {}", BlitText(blit)),
+            Fragment(fragment) => write!(
+                f,
+                "fragment {} {} {} {}",
+                fragment.header.message_id,
+                fragment.header.index,
+                fragment.header.count,
+                Hex(&fragment.data),
+            ),
+            Sequenced(msg) => write!(f, "sequenced {} {}", msg.seq, msg.shape),
+            Ack(seq) => write!(f, "ack {}", seq),
+            Nack(nack) => write!(f, "nack {} {}", nack.seq, reason_name(nack.reason)),
+            Rejected(rejected) => write!(
+                f,
+                "rejected {} {}",
+                reason_name(rejected.reason),
+                CellText(&rejected.cell),
+            ),
+            GetCell(pos) => write!(f, "getcell {} {}", pos.row, pos.column),
+            GetRegion(region) => write!(f, "getregion {}", RegionText(region)),
+            CellState(state) => write!(f, "cellstate {}", StateText(state)),
+            Join(join) => write!(f, "join 0x{:016x}", join.device_id),
+            Assigned(assigned) => write!(
+                f,
+                "assigned 0x{:016x} {} {}",
+                assigned.device_id,
+                assigned.short_addr,
+                RegionText(&assigned.partition),
+            ),
+            Heartbeat(heartbeat) => write!(f, "heartbeat {}", HeartbeatText(heartbeat)),
+            Ping(value) => write!(f, "ping {}", value),
+            Pong(value) => write!(f, "pong {}", value),
+        }
+    }
+}
+
+impl FromStr for RadioMessages {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        use crate::{Assigned, Join, Nack, Position, Rejected, Sequenced};
+
+        let mut tokens = Tokens::new(s);
+        let t = &mut tokens;
+        let keyword = t.next("a message")?;
+
+        let msg = match keyword {
+            "startturn" => RadioMessages::StartTurn(t.int("address")?),
+            "fragment" => RadioMessages::Fragment(Fragment {
+                header: FragmentHeader {
+                    message_id: t.int("message id")?,
+                    index: t.int("index")?,
+                    count: t.int("count")?,
+                },
+                data: heapless(&t.hex("data")?, "data")?,
+            }),
+            "sequenced" => {
+                let seq = t.int("sequence number")?;
+                let keyword = t.next("a shape")?;
+                match t.shape(keyword)? {
+                    Some(shape) => RadioMessages::Sequenced(Sequenced { seq, shape }),
+                    None => return error(format!("unknown shape `{}`", keyword)),
+                }
+            }
+            "ack" => RadioMessages::Ack(t.int("sequence number")?),
+            "nack" => RadioMessages::Nack(Nack {
+                seq: t.int("sequence number")?,
+                reason: t.reason()?,
+            }),
+            "rejected" => RadioMessages::Rejected(Rejected {
+                reason: t.reason()?,
+                cell: t.cell()?,
+            }),
+            "getcell" => RadioMessages::GetCell(Position {
+                row: t.coord("row")?,
+                column: t.coord("column")?,
+            }),
+            "getregion" => RadioMessages::GetRegion(t.region()?),
+            "cellstate" => RadioMessages::CellState(t.state()?),
+            "join" => RadioMessages::Join(Join {
+                device_id: t.int("device id")?,
+            }),
+            "assigned" => RadioMessages::Assigned(Assigned {
+                device_id: t.int("device id")?,
+                short_addr: t.int("address")?,
+                partition: t.region()?,
+            }),
+            "heartbeat" => RadioMessages::Heartbeat(t.heartbeat()?),
+            "ping" => RadioMessages::Ping(t.int("value")?),
+            "pong" => RadioMessages::Pong(t.int("value")?),
+            _ => match t.shape(keyword)? {
+                Some(shape) => shape.into(),
+                None => return error(format!("unknown message `{}`", keyword)),
+            },
+        };
+        tokens.end()?;
+
+        Ok(msg)
+    }
+}
+
+#[cfg(feature = "modem")]
+mod modem {
+    use super::*;
+    use crate::{
+        Acknowledge, BlitCommand, CellCommand, ClientKey, DataRate, DrawCommand, FrameAddress,
+        FrameHeader, FrameRejection, FrameType, HeartbeatCommand, Hello, ModemSettings,
+        ModemStatus, ModemUartMessages, PongReport, QueryCommand, RadioFrame, Reception,
+        RejectCommand, ReplyCommand, RxQuality, SniffedFrame, Version,
+    };
+
+    impl fmt::Display for ModemUartMessages {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            use ModemUartMessages::*;
+
+            match self {
+                SetCell(cmd) => write!(
+                    f,
+                    "setcell {} {} {}{}",
+                    cmd.source,
+                    cmd.dest,
+                    ReceptionText(&cmd.rx),
+                    CellText(&cmd.cell),
+                ),
+                Loopback(val) => write!(f, "loopback 0x{:08x}", val),
+                AnnounceTurn(addr) => write!(f, "announce {}", addr),
+                LoadLoopBack(vals) => {
+                    f.write_str("loadloopback")?;
+                    for val in vals.iter() {
+                        write!(f, " 0x{:x}", val)?;
+                    }
+                    Ok(())
+                }
+                SetCapture(enable) => write!(f, "capture {}", on_off(*enable)),
+                RadioFrame(frame) => write!(f, "frame {}", FrameText(frame)),
+                SetSniffer(enable) => write!(f, "sniffer {}", on_off(*enable)),
+                Sniffed(sniffed) => write!(
+                    f,
+                    "sniffed {} {}",
+                    Opt(&sniffed.rejection.map(rejection_name)),
+                    FrameText(&sniffed.frame),
+                ),
+                Configure(settings) => write!(f, "configure {}", SettingsText(settings)),
+                Configured(settings) => write!(f, "configured {}", SettingsText(settings)),
+                GetStatus => f.write_str("getstatus"),
+                Status(status) => write!(f, "status {}", StatusText(status)),
+                Hello(hello) => write!(
+                    f,
+                    "hello {} {} 0x{:x}",
+                    hello.protocol_version,
+                    hello.firmware_version,
+                    hello.capabilities,
+                ),
+                Draw(cmd) => write!(
+                    f,
+                    "draw {} {} {}{} {}",
+                    cmd.source,
+                    cmd.dest,
+                    ReceptionText(&cmd.rx),
+                    Opt(&cmd.seq),
+                    cmd.shape,
+                ),
+                Blit(cmd) => write!(
+                    f,
+                    "blit {} {} {}{}",
+                    cmd.source,
+                    cmd.dest,
+                    ReceptionText(&cmd.rx),
+                    BlitText(&cmd.blit),
+                ),
+                Acknowledge(ack) => write!(
+                    f,
+                    "ack {} {} {}",
+                    ack.client,
+                    ack.seq,
+                    Opt(&ack.rejection.map(reason_name)),
+                ),
+                Reject(reject) => write!(
+                    f,
+                    "reject {} {} {}",
+                    reject.client,
+                    reason_name(reject.rejected.reason),
+                    CellText(&reject.rejected.cell),
+                ),
+                Query(query) => write!(
+                    f,
+                    "query {} {} {}{}",
+                    query.source,
+                    query.dest,
+                    ReceptionText(&query.rx),
+                    RegionText(&query.region),
+                ),
+                Reply(reply) => write!(f, "reply {} {}", reply.client, StateText(&reply.state)),
+                Join(join) => write!(f, "join 0x{:016x}", join.device_id),
+                Assign(assigned) => write!(
+                    f,
+                    "assign 0x{:016x} {} {}",
+                    assigned.device_id,
+                    assigned.short_addr,
+                    RegionText(&assigned.partition),
+                ),
+                Heartbeat(cmd) => write!(
+                    f,
+                    "heartbeat {} {}{}",
+                    cmd.source,
+                    ReceptionText(&cmd.rx),
+                    HeartbeatText(&cmd.heartbeat),
+                ),
+                SetKey(key) => write!(f, "setkey {} {}", key.client, Hex(key.key.as_ref().map(|k| &k[..]).unwrap_or(&[]))),
+                Ping(dest) => write!(f, "ping {}", dest),
+                Pong(pong) => write!(f, "pong {} {} {}", pong.source, pong.rtt_us, QualityText(&pong.quality)),
+            }
+        }
+    }
+
+    impl FromStr for ModemUartMessages {
+        type Err = ParseError;
+
+        fn from_str(s: &str) -> Result<Self, ParseError> {
+            use crate::{Assigned, Join, Rejected};
+
+            let mut tokens = Tokens::new(s);
+            let t = &mut tokens;
+            let keyword = t.next("a message")?;
+
+            let msg = match keyword {
+                "setcell" => ModemUartMessages::SetCell(CellCommand {
+                    source: t.int("source")?,
+                    dest: t.int("destination")?,
+                    rx: t.reception()?,
+                    cell: t.cell()?,
+                }),
+                "loopback" => ModemUartMessages::Loopback(t.int("value")?),
+                "announce" => ModemUartMessages::AnnounceTurn(t.int("address")?),
+                "loadloopback" => {
+                    let mut vals = [0; 16];
+                    for val in vals.iter_mut() {
+                        *val = t.int("value")?;
+                    }
+                    ModemUartMessages::LoadLoopBack(vals)
+                }
+                "capture" => ModemUartMessages::SetCapture(t.on_off()?),
+                "frame" => ModemUartMessages::RadioFrame(t.frame()?),
+                "sniffer" => ModemUartMessages::SetSniffer(t.on_off()?),
+                "sniffed" => ModemUartMessages::Sniffed(SniffedFrame {
+                    rejection: t.optional(|t| t.rejection())?,
+                    frame: t.frame()?,
+                }),
+                "configure" => ModemUartMessages::Configure(t.settings()?),
+                "configured" => ModemUartMessages::Configured(t.settings()?),
+                "getstatus" => ModemUartMessages::GetStatus,
+                "status" => ModemUartMessages::Status(t.status()?),
+                "hello" => ModemUartMessages::Hello(Hello {
+                    protocol_version: t.int("protocol version")?,
+                    firmware_version: t.version()?,
+                    capabilities: t.int("capabilities")?,
+                }),
+                "draw" => {
+                    let source = t.int("source")?;
+                    let dest = t.int("destination")?;
+                    let rx = t.reception()?;
+                    let seq = t.optional(|t| t.int("sequence number"))?;
+                    let keyword = t.next("a shape")?;
+                    match t.shape(keyword)? {
+                        Some(shape) => ModemUartMessages::Draw(DrawCommand { source, dest, rx, shape, seq }),
+                        None => return error(format!("unknown shape `{}`", keyword)),
+                    }
+                }
+                "blit" => ModemUartMessages::Blit(BlitCommand {
+                    source: t.int("source")?,
+                    dest: t.int("destination")?,
+                    rx: t.reception()?,
+                    blit: t.blit()?,
+                }),
+                "ack" => ModemUartMessages::Acknowledge(Acknowledge {
+                    client: t.int("client")?,
+                    seq: t.int("sequence number")?,
+                    rejection: t.optional(|t| t.reason())?,
+                }),
+                "reject" => ModemUartMessages::Reject(RejectCommand {
+                    client: t.int("client")?,
+                    rejected: Rejected {
+                        reason: t.reason()?,
+                        cell: t.cell()?,
+                    },
+                }),
+                "query" => ModemUartMessages::Query(QueryCommand {
+                    source: t.int("source")?,
+                    dest: t.int("destination")?,
+                    rx: t.reception()?,
+                    region: t.region()?,
+                }),
+                "reply" => ModemUartMessages::Reply(ReplyCommand {
+                    client: t.int("client")?,
+                    state: t.state()?,
+                }),
+                "join" => ModemUartMessages::Join(Join {
+                    device_id: t.int("device id")?,
+                }),
+                "assign" => ModemUartMessages::Assign(Assigned {
+                    device_id: t.int("device id")?,
+                    short_addr: t.int("address")?,
+                    partition: t.region()?,
+                }),
+                "heartbeat" => ModemUartMessages::Heartbeat(HeartbeatCommand {
+                    source: t.int("source")?,
+                    rx: t.reception()?,
+                    heartbeat: t.heartbeat()?,
+                }),
+                "setkey" => {
+                    let client = t.int("client")?;
+                    let key = t.hex("key")?;
+                    let key = match key.len() {
+                        0 => None,
+                        16 => {
+                            let mut bytes = [0; 16];
+                            bytes.copy_from_slice(&key);
+                            Some(bytes)
+                        }
+                        _ => return error("a key must be 16 bytes".into()),
+                    };
+                    ModemUartMessages::SetKey(ClientKey { client, key })
+                }
+                "ping" => ModemUartMessages::Ping(t.int("destination")?),
+                "pong" => ModemUartMessages::Pong(PongReport {
+                    source: t.int("source")?,
+                    rtt_us: t.int("round trip time")?,
+                    quality: t.quality()?,
+                }),
+                _ => return error(format!("unknown message `{}`", keyword)),
+            };
+            tokens.end()?;
+
+            Ok(msg)
+        }
+    }
+
+    fn on_off(enable: bool) -> &'static str {
+        if enable {
+            "on"
+        } else {
+            "off"
+        }
+    }
+
+    fn rejection_name(rejection: FrameRejection) -> &'static str {
+        match rejection {
+            FrameRejection::BadSource => "bad_source",
+            FrameRejection::BadDestination => "bad_destination",
+            FrameRejection::BroadcastPan => "broadcast_pan",
+            FrameRejection::BroadcastSource => "broadcast_source",
+            FrameRejection::PanMismatch => "pan_mismatch",
+            FrameRejection::NotForModem => "not_for_modem",
+            FrameRejection::DecodeFailed => "decode_failed",
+            FrameRejection::Unexpected => "unexpected",
+            FrameRejection::BadFragment => "bad_fragment",
+            FrameRejection::BadTag => "bad_tag",
+            FrameRejection::Unsupported => "unsupported",
+        }
+    }
+
+    const REJECTIONS: [FrameRejection; 11] = [
+        FrameRejection::BadSource,
+        FrameRejection::BadDestination,
+        FrameRejection::BroadcastPan,
+        FrameRejection::BroadcastSource,
+        FrameRejection::PanMismatch,
+        FrameRejection::NotForModem,
+        FrameRejection::DecodeFailed,
+        FrameRejection::Unexpected,
+        FrameRejection::BadFragment,
+        FrameRejection::BadTag,
+        FrameRejection::Unsupported,
+    ];
+
+    fn data_rate_name(rate: DataRate) -> &'static str {
+        match rate {
+            DataRate::Kbps110 => "kbps110",
+            DataRate::Kbps850 => "kbps850",
+            DataRate::Mbps6_8 => "mbps6_8",
+        }
+    }
+
+    fn frame_type_name(frame_type: FrameType) -> &'static str {
+        match frame_type {
+            FrameType::Beacon => "beacon",
+            FrameType::Data => "data",
+            FrameType::Acknowledgement => "ack",
+            FrameType::MacCommand => "command",
+        }
+    }
+
+    struct FrameText<'a>(&'a RadioFrame);
+
+    impl fmt::Display for FrameText<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let hdr = &self.0.header;
+            let flags: Vec<&str> = [
+                (hdr.frame_pending, "pending"),
+                (hdr.ack_request, "ackreq"),
+                (hdr.pan_id_compress, "compress"),
+            ]
+                .iter()
+                .filter(|(set, _)| *set)
+                .map(|(_, name)| *name)
+                .collect();
+
+            write!(
+                f,
+                "{} {} {} {} {} {}",
+                frame_type_name(hdr.frame_type),
+                hdr.seq,
+                if flags.is_empty() { "-".into() } else { flags.join(",") },
+                AddressText(&hdr.destination),
+                AddressText(&hdr.source),
+                Hex(&self.0.payload),
+            )
+        }
+    }
+
+    struct AddressText<'a>(&'a FrameAddress);
+
+    impl fmt::Display for AddressText<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match *self.0 {
+                FrameAddress::None => f.write_str("-"),
+                FrameAddress::Short { pan, addr } => write!(f, "{:04x}:{:04x}", pan, addr),
+                FrameAddress::Extended { pan, addr } => write!(f, "{:04x}:{:016x}", pan, addr),
+            }
+        }
+    }
+
+    struct SettingsText<'a>(&'a ModemSettings);
+
+    impl fmt::Display for SettingsText<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let s = self.0;
+            write!(
+                f,
+                "pan_id=0x{:04x} short_addr=0x{:04x} broadcast_pan=0x{:04x} channel={} data_rate={} rx_window_us={}",
+                s.pan_id,
+                s.short_addr,
+                s.broadcast_pan,
+                s.channel,
+                data_rate_name(s.data_rate),
+                s.rx_window_us,
+            )
+        }
+    }
+
+    struct QualityText<'a>(&'a RxQuality);
+
+    impl fmt::Display for QualityText<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let q = self.0;
+            write!(
+                f,
+                "cir_power={} fp_ampl={},{},{} preamble_count={} std_noise={} prf={}",
+                q.cir_power,
+                q.fp_ampl[0],
+                q.fp_ampl[1],
+                q.fp_ampl[2],
+                q.preamble_count,
+                q.std_noise,
+                if q.prf_64mhz { 64 } else { 16 },
+            )
+        }
+    }
+
+    /// Followed by a space, to be written in front of the next field
+    struct ReceptionText<'a>(&'a Reception);
+
+    impl fmt::Display for ReceptionText<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "timestamp_us={} {} ", self.0.timestamp_us, QualityText(&self.0.quality))
+        }
+    }
+
+    struct StatusText<'a>(&'a ModemStatus);
+
+    impl fmt::Display for StatusText<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let s = self.0;
+            write!(
+                f,
+                "uptime_ms={} frames_received={} lost_bytes={} lost_msgs={} rx_errors={} reassembly_timeouts={} crc_errors={}",
+                s.uptime_ms,
+                s.frames_received,
+                s.lost_bytes,
+                s.lost_msgs,
+                s.rx_errors,
+                s.reassembly_timeouts,
+                s.crc_errors,
+            )?;
+
+            let mut counters = s.rejected;
+            for rejection in REJECTIONS.iter() {
+                write!(f, " {}={}", rejection_name(*rejection), counter(&mut counters, *rejection))?;
+            }
+
+            Ok(())
+        }
+    }
+
+    /// The counter of `RejectionCounters` for `rejection`
+    fn counter(counters: &mut crate::RejectionCounters, rejection: FrameRejection) -> &mut u32 {
+        match rejection {
+            FrameRejection::BadSource => &mut counters.bad_source,
+            FrameRejection::BadDestination => &mut counters.bad_destination,
+            FrameRejection::BroadcastPan => &mut counters.broadcast_pan,
+            FrameRejection::BroadcastSource => &mut counters.broadcast_source,
+            FrameRejection::PanMismatch => &mut counters.pan_mismatch,
+            FrameRejection::NotForModem => &mut counters.not_for_modem,
+            FrameRejection::DecodeFailed => &mut counters.decode_failed,
+            FrameRejection::Unexpected => &mut counters.unexpected,
+            FrameRejection::BadFragment => &mut counters.bad_fragment,
+            FrameRejection::BadTag => &mut counters.bad_tag,
+            FrameRejection::Unsupported => &mut counters.unsupported,
+        }
+    }
+
+    /// Set the field of `quality` called `key`
+    fn quality_field(quality: &mut RxQuality, key: &str, value: &str) -> Result<(), ParseError> {
+        match key {
+            "cir_power" => quality.cir_power = int(key, value)?,
+            "preamble_count" => quality.preamble_count = int(key, value)?,
+            "std_noise" => quality.std_noise = int(key, value)?,
+            "fp_ampl" => {
+                let mut ampls = value.split(',');
+                for ampl in quality.fp_ampl.iter_mut() {
+                    *ampl = int(key, ampls.next().unwrap_or(""))?;
+                }
+                if ampls.next().is_some() {
+                    return error(format!("fp_ampl takes three values, got `{}`", value));
+                }
+            }
+            "prf" => {
+                quality.prf_64mhz = match value {
+                    "16" => false,
+                    "64" => true,
+                    _ => return error(format!("prf must be 16 or 64, got `{}`", value)),
+                }
+            }
+            _ => return error(format!("unknown quality `{}`", key)),
+        }
+
+        Ok(())
+    }
+
+    impl<'a> Tokens<'a> {
+        fn on_off(&mut self) -> Result<bool, ParseError> {
+            match self.next("on or off")? {
+                "on" => Ok(true),
+                "off" => Ok(false),
+                other => error(format!("expected on or off, got `{}`", other)),
+            }
+        }
+
+        fn rejection(&mut self) -> Result<FrameRejection, ParseError> {
+            let name = self.next("rejection")?;
+            REJECTIONS
+                .iter()
+                .cloned()
+                .find(|r| rejection_name(*r) == name)
+                .ok_or_else(|| ParseError(format!("unknown rejection `{}`", name)))
+        }
+
+        fn version(&mut self) -> Result<Version, ParseError> {
+            let token = self.next("version")?;
+            let mut parts = token.split('.').map(u16::from_str);
+
+            match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch)), None) => Ok(Version { major, minor, patch }),
+                _ => error(format!("invalid version `{}`, expected e.g. 0.1.0", token)),
+            }
+        }
+
+        fn address(&mut self) -> Result<FrameAddress, ParseError> {
+            let token = self.next("address")?;
+            if token == "-" {
+                return Ok(FrameAddress::None);
+            }
+
+            let invalid = || ParseError(format!("invalid address `{}`, expected e.g. 0386:0808", token));
+            let mut parts = token.splitn(2, ':');
+            let pan = parts.next().and_then(|pan| u16::from_str_radix(pan, 16).ok()).ok_or_else(invalid)?;
+            let addr = parts.next().ok_or_else(invalid)?;
+
+            if addr.len() > 4 {
+                let addr = u64::from_str_radix(addr, 16).map_err(|_| invalid())?;
+                Ok(FrameAddress::Extended { pan, addr })
+            } else {
+                let addr = u16::from_str_radix(addr, 16).map_err(|_| invalid())?;
+                Ok(FrameAddress::Short { pan, addr })
+            }
+        }
+
+        fn frame(&mut self) -> Result<RadioFrame, ParseError> {
+            let frame_type = match self.next("frame type")? {
+                "beacon" => FrameType::Beacon,
+                "data" => FrameType::Data,
+                "ack" => FrameType::Acknowledgement,
+                "command" => FrameType::MacCommand,
+                other => return error(format!("unknown frame type `{}`", other)),
+            };
+            let seq = self.int("sequence number")?;
+
+            let flags = self.next("flags")?;
+            let mut header = FrameHeader {
+                frame_type,
+                frame_pending: false,
+                ack_request: false,
+                pan_id_compress: false,
+                seq,
+                destination: FrameAddress::None,
+                source: FrameAddress::None,
+            };
+            for flag in flags.split(',').filter(|flag| *flag != "-") {
+                match flag {
+                    "pending" => header.frame_pending = true,
+                    "ackreq" => header.ack_request = true,
+                    "compress" => header.pan_id_compress = true,
+                    other => return error(format!("unknown flag `{}`", other)),
+                }
+            }
+            header.destination = self.address()?;
+            header.source = self.address()?;
+
+            Ok(RadioFrame {
+                header,
+                payload: heapless(&self.hex("payload")?, "payload")?,
+            })
+        }
+
+        /// `key=value` pairs, unset keys keep their default
+        fn settings(&mut self) -> Result<ModemSettings, ParseError> {
+            let mut settings = ModemSettings::default();
+
+            while let Some((key, value)) = self.pair()? {
+                match key {
+                    "pan_id" => settings.pan_id = int(key, value)?,
+                    "short_addr" => settings.short_addr = int(key, value)?,
+                    "broadcast_pan" => settings.broadcast_pan = int(key, value)?,
+                    "channel" => settings.channel = int(key, value)?,
+                    "rx_window_us" => settings.rx_window_us = int(key, value)?,
+                    "data_rate" => {
+                        settings.data_rate = match value {
+                            "kbps110" => DataRate::Kbps110,
+                            "kbps850" => DataRate::Kbps850,
+                            "mbps6_8" => DataRate::Mbps6_8,
+                            _ => return error(format!("unknown data rate `{}`", value)),
+                        }
+                    }
+                    _ => return error(format!("unknown setting `{}`", key)),
+                }
+            }
+
+            Ok(settings)
+        }
+
+        /// `key=value` pairs, unset counters are zero
+        fn status(&mut self) -> Result<ModemStatus, ParseError> {
+            let mut status = ModemStatus::default();
+
+            while let Some((key, value)) = self.pair()? {
+                let field = match key {
+                    "uptime_ms" => &mut status.uptime_ms,
+                    "frames_received" => &mut status.frames_received,
+                    "lost_bytes" => &mut status.lost_bytes,
+                    "lost_msgs" => &mut status.lost_msgs,
+                    "rx_errors" => &mut status.rx_errors,
+                    "reassembly_timeouts" => &mut status.reassembly_timeouts,
+                    "crc_errors" => &mut status.crc_errors,
+                    _ => match REJECTIONS.iter().find(|r| rejection_name(**r) == key) {
+                        Some(rejection) => counter(&mut status.rejected, *rejection),
+                        None => return error(format!("unknown counter `{}`", key)),
+                    },
+                };
+                *field = int(key, value)?;
+            }
+
+            Ok(status)
+        }
+
+        /// `key=value` pairs, unset values are zero
+        fn quality(&mut self) -> Result<RxQuality, ParseError> {
+            let mut quality = RxQuality::default();
+
+            while let Some((key, value)) = self.pair()? {
+                quality_field(&mut quality, key, value)?;
+            }
+
+            Ok(quality)
+        }
+
+        /// `key=value` pairs up to the next field, which never contains
+        /// a `=`. Unset values are zero, so they may be left out.
+        fn reception(&mut self) -> Result<Reception, ParseError> {
+            let mut rx = Reception::default();
+
+            while matches!(self.tokens.peek(), Some(token) if token.contains('=')) {
+                let (key, value) = self.pair()?.expect("peeked a token");
+                match key {
+                    "timestamp_us" => rx.timestamp_us = int(key, value)?,
+                    _ => quality_field(&mut rx.quality, key, value)?,
+                }
+            }
+
+            Ok(rx)
+        }
+
+        fn pair(&mut self) -> Result<Option<(&'a str, &'a str)>, ParseError> {
+            let token = match self.tokens.next() {
+                Some(token) => token,
+                None => return Ok(None),
+            };
+
+            let mut parts = token.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => Ok(Some((key, value))),
+                _ => error(format!("expected key=value, got `{}`", token)),
+            }
+        }
+    }
+}
+
+fn reason_name(reason: RejectReason) -> &'static str {
+    match reason {
+        RejectReason::OutOfRange => "out_of_range",
+        RejectReason::NotYourTurn => "not_your_turn",
+        RejectReason::RateLimited => "rate_limited",
+    }
+}
+
+fn color_of(cell: &Cell) -> Color {
+    Color {
+        red: cell.red,
+        green: cell.green,
+        blue: cell.blue,
+    }
+}
+
+fn encoding_name(encoding: BlitEncoding) -> &'static str {
+    match encoding {
+        BlitEncoding::Packed => "packed",
+        BlitEncoding::RunLength => "run_length",
+    }
+}
+
+/// Parse a number, in decimal or with a `0x` prefix in hex
+fn int<T: TryFrom<u64>>(what: &str, token: &str) -> Result<T, ParseError> {
+    let value = match token.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => token.parse().ok(),
+    };
+
+    value
+        .and_then(|value| T::try_from(value).ok())
+        .ok_or_else(|| ParseError(format!("invalid {} `{}`", what, token)))
+}
+
+fn heapless<T: Clone, N: ArrayLength<T>>(items: &[T], what: &str) -> Result<heapless::Vec<T, N>, ParseError> {
+    let mut vec = heapless::Vec::new();
+    vec.extend_from_slice(items)
+        .map_err(|_| ParseError(format!("too much {}", what)))?;
+
+    Ok(vec)
+}
+
+struct Tokens<'a> {
+    tokens: Peekable<SplitWhitespace<'a>>,
+}
+
+impl<'a> Tokens<'a> {
+    fn new(s: &'a str) -> Self {
+        Tokens {
+            tokens: s.split_whitespace().peekable(),
+        }
+    }
+
+    fn next(&mut self, what: &str) -> Result<&'a str, ParseError> {
+        self.tokens
+            .next()
+            .ok_or_else(|| ParseError(format!("missing {}", what)))
+    }
+
+    fn is_empty(&mut self) -> bool {
+        self.tokens.peek().is_none()
+    }
+
+    fn end(&mut self) -> Result<(), ParseError> {
+        match self.tokens.next() {
+            Some(token) => error(format!("unexpected `{}`", token)),
+            None => Ok(()),
+        }
+    }
+
+    /// `None` if the next token is `-`, otherwise parse it with `parse`
+    fn optional<T, F>(&mut self, parse: F) -> Result<Option<T>, ParseError>
+    where
+        F: FnOnce(&mut Self) -> Result<T, ParseError>,
+    {
+        if self.tokens.peek() == Some(&"-") {
+            self.tokens.next();
+            Ok(None)
+        } else {
+            parse(self).map(Some)
+        }
+    }
+
+    fn int<T: TryFrom<u64>>(&mut self, what: &str) -> Result<T, ParseError> {
+        int(what, self.next(what)?)
+    }
+
+    fn coord(&mut self, what: &str) -> Result<Coord, ParseError> {
+        let token = self.next(what)?;
+        int::<u8>(what, token)
+            .ok()
+            .and_then(Coord::new)
+            .ok_or_else(|| ParseError(format!("invalid {} `{}`, expected 1 to 255", what, token)))
+    }
+
+    fn color(&mut self) -> Result<Color, ParseError> {
+        self.next("colour")?.parse()
+    }
+
+    /// Bytes in hex, `-` if there are none
+    fn hex(&mut self, what: &str) -> Result<Vec<u8>, ParseError> {
+        let token = self.next(what)?;
+        if token == "-" {
+            return Ok(Vec::new());
+        }
+
+        let invalid = || ParseError(format!("invalid {} `{}`, expected hex", what, token));
+        if token.len() % 2 != 0 || !token.is_ascii() {
+            return Err(invalid());
+        }
+
+        (0..token.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&token[i..i + 2], 16).map_err(|_| invalid()))
+            .collect()
+    }
+
+    fn reason(&mut self) -> Result<RejectReason, ParseError> {
+        match self.next("reason")? {
+            "out_of_range" => Ok(RejectReason::OutOfRange),
+            "not_your_turn" => Ok(RejectReason::NotYourTurn),
+            "rate_limited" => Ok(RejectReason::RateLimited),
+            other => error(format!("unknown reason `{}`", other)),
+        }
+    }
+
+    fn cell(&mut self) -> Result<Cell, ParseError> {
+        let row = self.coord("row")?;
+        let column = self.coord("column")?;
+        let color = self.color()?;
+
+        Ok(Cell {
+            row,
+            column,
+            red: color.red,
+            green: color.green,
+            blue: color.blue,
+        })
+    }
+
+    fn region(&mut self) -> Result<Region, ParseError> {
+        Ok(Region {
+            row: self.coord("row")?,
+            column: self.coord("column")?,
+            width: self.int("width")?,
+            height: self.int("height")?,
+        })
+    }
+
+    fn state(&mut self) -> Result<CellState, ParseError> {
+        let region = self.region()?;
+        let offset = self.int("offset")?;
+
+        let mut colors = Vec::new();
+        while !self.is_empty() {
+            colors.push(self.optional(|t| t.color())?);
+        }
+
+        Ok(CellState {
+            region,
+            offset,
+            colors: heapless(&colors, "colours")?,
+        })
+    }
+
+    fn heartbeat(&mut self) -> Result<Heartbeat, ParseError> {
+        let battery_mv = self.optional(|t| t.int("battery voltage"))?;
+
+        // The firmware name is the rest of the line
+        let rest: Vec<&str> = self.tokens.by_ref().collect();
+        let firmware = match rest.as_slice() {
+            ["-"] => None,
+            words => {
+                let mut firmware = heapless::String::new();
+                firmware
+                    .push_str(&words.join(" "))
+                    .map_err(|_| ParseError("firmware name too long".into()))?;
+                Some(firmware)
+            }
+        };
+
+        Ok(Heartbeat { battery_mv, firmware })
+    }
+
+    fn blit(&mut self) -> Result<Blit, ParseError> {
+        let row = self.coord("row")?;
+        let column = self.coord("column")?;
+        let width = self.int("width")?;
+        let height = self.int("height")?;
+
+        let encoding = match self.next("encoding")? {
+            "packed" => BlitEncoding::Packed,
+            "run_length" => BlitEncoding::RunLength,
+            other => return error(format!("unknown encoding `{}`", other)),
+        };
+
+        let palette = self.next("palette")?;
+        let palette = if palette == "-" {
+            Vec::new()
+        } else {
+            palette
+                .split(',')
+                .map(Color::from_str)
+                .collect::<Result<Vec<_>, _>>()?
+        };
+
+        Ok(Blit {
+            row,
+            column,
+            width,
+            height,
+            palette: heapless(&palette, "colours")?,
+            encoding,
+            data: heapless(&self.hex("data")?, "data")?,
+        })
+    }
+
+    /// The shape written with `keyword`, if it names one
+    fn shape(&mut self, keyword: &str) -> Result<Option<Shape>, ParseError> {
+        let shape = match keyword {
+            "setcell" => Shape::Cell(self.cell()?),
+            "fillrect" => Shape::FillRect(Rect {
+                row: self.coord("row")?,
+                column: self.coord("column")?,
+                width: self.int("width")?,
+                height: self.int("height")?,
+                color: self.color()?,
+            }),
+            "line" => Shape::Line(Line {
+                from_row: self.coord("row")?,
+                from_column: self.coord("column")?,
+                to_row: self.coord("row")?,
+                to_column: self.coord("column")?,
+                color: self.color()?,
+            }),
+            "setrow" => {
+                let row = self.coord("row")?;
+                let column = self.coord("column")?;
+
+                let mut colors = Vec::new();
+                while !self.is_empty() {
+                    colors.push(self.color()?);
+                }
+
+                Shape::Row(Row {
+                    row,
+                    column,
+                    colors: heapless(&colors, "colours")?,
+                })
+            }
+            "setcells" => {
+                let mut cells = Vec::new();
+                while !self.is_empty() {
+                    cells.push(self.cell()?);
+                }
+
+                Shape::Cells(heapless(&cells, "cells")?)
+            }
+            "blit" => Shape::Blit(self.blit()?),
+            _ => return Ok(None),
+        };
+
+        Ok(Some(shape))
+    }
+}
+
+/// Bytes in hex, `-` if there are none
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_empty() {
+            return f.write_str("-");
+        }
+
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// An optional field, `-` if missing
+struct Opt<'a, T>(&'a Option<T>);
+
+impl<T: fmt::Display> fmt::Display for Opt<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(value) => value.fmt(f),
+            None => f.write_str("-"),
+        }
+    }
+}
+
+struct CellText<'a>(&'a Cell);
+
+impl fmt::Display for CellText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}", self.0.row, self.0.column, color_of(self.0))
+    }
+}
+
+struct RectText<'a>(&'a Rect);
+
+impl fmt::Display for RectText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rect = self.0;
+        write!(f, "{} {} {} {} {}", rect.row, rect.column, rect.width, rect.height, rect.color)
+    }
+}
+
+struct LineText<'a>(&'a Line);
+
+impl fmt::Display for LineText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let line = self.0;
+        write!(
+            f,
+            "{} {} {} {} {}",
+            line.from_row,
+            line.from_column,
+            line.to_row,
+            line.to_column,
+            line.color,
+        )
+    }
+}
+
+struct RowText<'a>(&'a Row);
+
+impl fmt::Display for RowText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.0.row, self.0.column)?;
+        for color in self.0.colors.iter() {
+            write!(f, " {}", color)?;
+        }
+
+        Ok(())
+    }
+}
+
+struct BlitText<'a>(&'a Blit);
+
+impl fmt::Display for BlitText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let blit = self.0;
+        write!(
+            f,
+            "{} {} {} {} {} ",
+            blit.row,
+            blit.column,
+            blit.width,
+            blit.height,
+            encoding_name(blit.encoding),
+        )?;
+
+        if blit.palette.is_empty() {
+            f.write_str("-")?;
+        }
+        for (i, color) in blit.palette.iter().enumerate() {
+            if i != 0 {
+                f.write_str(",")?;
+            }
+            color.fmt(f)?;
+        }
+
+        write!(f, " {}", Hex(&blit.data))
+    }
+}
+
+struct RegionText<'a>(&'a Region);
+
+impl fmt::Display for RegionText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let region = self.0;
+        write!(f, "{} {} {} {}", region.row, region.column, region.width, region.height)
+    }
+}
+
+struct StateText<'a>(&'a CellState);
+
+impl fmt::Display for StateText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", RegionText(&self.0.region), self.0.offset)?;
+        for color in self.0.colors.iter() {
+            write!(f, " {}", Opt(color))?;
+        }
+
+        Ok(())
+    }
+}
+
+struct HeartbeatText<'a>(&'a Heartbeat);
+
+impl fmt::Display for HeartbeatText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ", Opt(&self.0.battery_mv))?;
+        match self.0.firmware {
+            Some(ref firmware) => f.write_str(firmware),
+            None => f.write_str("-"),
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Messages as bytes on the wire
+//!
+//! Radio frames carry a postcard serialized `RadioMessages` as their
+//! payload. Commands to the modem are a postcard serialized
+//! `ModemUartMessages`, wrapped in a `CheckedFrame` and framed with
+//! COBS, including the terminating zero. The modem's replies are wrapped
+//! once more by its logger, which is not part of this crate.
+
+use std::fmt;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+#[cfg(feature = "modem")]
+use crate::{CheckError, CheckedFrame, ModemUartMessages};
+
+#[derive(Debug)]
+pub enum WireError {
+    Postcard(postcard::Error),
+    #[cfg(feature = "modem")]
+    Check(CheckError),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WireError::Postcard(err) => write!(f, "postcard: {:?}", err),
+            #[cfg(feature = "modem")]
+            WireError::Check(CheckError::TooLarge) => f.write_str("message too large"),
+            #[cfg(feature = "modem")]
+            WireError::Check(CheckError::Mismatch) => f.write_str("CRC mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+impl From<postcard::Error> for WireError {
+    fn from(err: postcard::Error) -> Self {
+        WireError::Postcard(err)
+    }
+}
+
+#[cfg(feature = "modem")]
+impl From<CheckError> for WireError {
+    fn from(err: CheckError) -> Self {
+        WireError::Check(err)
+    }
+}
+
+/// Serialize a message, e.g. a `RadioMessages` for a frame payload
+pub fn to_bytes<T: Serialize>(msg: &T) -> Result<Vec<u8>, WireError> {
+    Ok(postcard::to_stdvec(msg)?)
+}
+
+pub fn from_bytes<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, WireError> {
+    Ok(postcard::from_bytes(bytes)?)
+}
+
+/// Serialize a message and frame it with COBS
+pub fn to_cobs<T: Serialize>(msg: &T) -> Result<Vec<u8>, WireError> {
+    Ok(postcard::to_stdvec_cobs(msg)?)
+}
+
+/// Decode a COBS frame, with or without its terminating zero
+pub fn from_cobs<T: DeserializeOwned>(frame: &[u8]) -> Result<T, WireError> {
+    let mut frame = frame.to_vec();
+    Ok(postcard::from_bytes_cobs(&mut frame)?)
+}
+
+/// Encode a command as the host sends it to the modem
+#[cfg(feature = "modem")]
+pub fn to_uart(msg: &ModemUartMessages) -> Result<Vec<u8>, WireError> {
+    let frame = CheckedFrame::new(&to_bytes(msg)?)?;
+    to_cobs(&frame)
+}
+
+/// Decode a command as the modem receives it from the host
+#[cfg(feature = "modem")]
+pub fn from_uart(frame: &[u8]) -> Result<ModemUartMessages, WireError> {
+    from_checked(&from_cobs(frame)?)
+}
+
+/// The message in a `CheckedFrame`, if it matches its CRC
+#[cfg(feature = "modem")]
+pub fn from_checked(frame: &CheckedFrame) -> Result<ModemUartMessages, WireError> {
+    from_bytes(frame.check()?)
+}